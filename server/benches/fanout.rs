@@ -0,0 +1,158 @@
+//! Publish to a growing number of subscribers over loopback, and encode the
+//! `MSG` each of them receives.
+//!
+//! The fan-out also counts the heap allocations made per delivered message,
+//! by the server and the clients alike. The published subject and payload
+//! are shared by every delivery, so the count drops as subscribers are added
+//! instead of growing with them.
+
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::future::join_all;
+use rnats::{
+    options::ServerOptions,
+    protocol::{Msg, NatsMessageCodec, ServerOp},
+    server,
+    subject::Subject,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    sync::oneshot,
+};
+use tokio_util::codec::Encoder;
+
+const MSGS: usize = 1000;
+const PAYLOAD: usize = 128;
+
+/// The system allocator, counting allocations.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Connect, send `ops` and wait until the server has processed them.
+async fn client(addr: SocketAddr, ops: &[u8]) -> BufReader<TcpStream> {
+    let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let mut line = String::new();
+    conn.read_line(&mut line).await.unwrap();
+    assert!(line.starts_with("INFO "));
+    conn.get_mut().write_all(ops).await.unwrap();
+    conn.get_mut().write_all(b"PING\r\n").await.unwrap();
+    line.clear();
+    conn.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "PONG\r\n");
+    conn
+}
+
+fn fanout(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (shutdown, stop) = oneshot::channel::<()>();
+    let addr = rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server::run(listener, ServerOptions::default(), stop));
+        addr
+    });
+
+    let mut batch = Vec::new();
+    for _ in 0..MSGS {
+        batch.extend_from_slice(format!("PUB foo {}\r\n", PAYLOAD).as_bytes());
+        batch.extend_from_slice(&[b'x'; PAYLOAD]);
+        batch.extend_from_slice(b"\r\n");
+    }
+    let expected = MSGS * (format!("MSG foo 1 {}\r\n\r\n", PAYLOAD).len() + PAYLOAD);
+
+    let mut group = c.benchmark_group("fanout");
+    for subs in [1, 10, 100] {
+        let (mut publisher, mut subscribers) = rt.block_on(async {
+            let publisher = client(addr, b"CONNECT {}\r\n").await;
+            let mut subscribers = Vec::new();
+            for _ in 0..subs {
+                subscribers.push(client(addr, b"CONNECT {}\r\nSUB foo 1\r\n").await);
+            }
+            (publisher, subscribers)
+        });
+
+        let (mut allocations, mut delivered) = (0, 0);
+        group.throughput(Throughput::Elements((MSGS * subs) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(subs), &subs, |b, _| {
+            b.iter_custom(|iters| {
+                rt.block_on(async {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let start = Instant::now();
+                        let before = ALLOCATIONS.load(Ordering::Relaxed);
+                        let reads = join_all(subscribers.iter_mut().map(|sub| async move {
+                            let mut buf = vec![0; expected];
+                            sub.read_exact(&mut buf).await.unwrap();
+                        }));
+                        let (res, _) = tokio::join!(publisher.get_mut().write_all(&batch), reads);
+                        res.unwrap();
+                        elapsed += start.elapsed();
+                        allocations += ALLOCATIONS.load(Ordering::Relaxed) - before;
+                        delivered += MSGS * subs;
+                    }
+                    elapsed
+                })
+            })
+        });
+        println!(
+            "fanout/{}: {:.3} allocations per delivered message",
+            subs,
+            allocations as f64 / delivered as f64
+        );
+    }
+    group.finish();
+    let _ = shutdown.send(());
+}
+
+fn encode(c: &mut Criterion) {
+    let mut codec = NatsMessageCodec::new();
+    let subject = Subject::from_static("foo.bar");
+    let sid = Bytes::from_static(b"1");
+    let payload = Bytes::from(vec![b'x'; PAYLOAD]);
+    let mut buf = BytesMut::with_capacity(64 * 1024);
+
+    c.bench_function("encode_msg", |b| {
+        b.iter(|| {
+            let msg = Msg {
+                subject: subject.clone(),
+                sid: sid.clone(),
+                reply: None,
+                headers: None,
+                payload: payload.clone(),
+            };
+            codec.encode(ServerOp::Msg(msg), &mut buf).unwrap();
+            buf.clear();
+        })
+    });
+}
+
+criterion_group!(benches, fanout, encode);
+criterion_main!(benches);
@@ -1,3 +1,4 @@
+#![allow(dead_code)]
 use tokio_util::codec::Decoder;
 use bytes::{BytesMut, Buf};
 
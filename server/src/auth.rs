@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use tokio::task;
+
+use crate::{connect::Connect, options::ServerOptions};
+
+/// Prefix of the secrets hashed with bcrypt, e.g. `$2a$11$...`.
+const BCRYPT_PREFIX: &str = "$2";
+
+/// How clients authenticate, set with the `authorization` block of the
+/// configuration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Authorization {
+    /// Clients send this token as `auth_token`.
+    Token(String),
+    /// Clients send the name and password of one of these users.
+    Users(Vec<User>),
+}
+
+/// A user allowed to connect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub username: String,
+    /// In plain text, or hashed with bcrypt.
+    pub password: String,
+}
+
+impl Authorization {
+    /// Returns `true` if the credentials the client sent in `CONNECT` are
+    /// accepted.
+    pub fn check(&self, connect: &Connect) -> bool {
+        match self {
+            Authorization::Token(token) => connect
+                .auth_token
+                .as_deref()
+                .is_some_and(|given| verify(token, given)),
+            Authorization::Users(users) => {
+                let (user, pass) = match (connect.user.as_deref(), connect.pass.as_deref()) {
+                    (Some(user), Some(pass)) => (user, pass),
+                    _ => return false,
+                };
+                users
+                    .iter()
+                    .find(|u| u.username == user)
+                    .is_some_and(|u| verify(&u.password, pass))
+            }
+        }
+    }
+}
+
+/// Compare a secret sent by a client with the expected one, which may be
+/// hashed with bcrypt.
+fn verify(expected: &str, given: &str) -> bool {
+    if expected.starts_with(BCRYPT_PREFIX) {
+        return bcrypt::verify(given, expected).unwrap_or(false);
+    }
+    // In constant time, so the time taken does not tell how much matched.
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Check the credentials in `connect` against the authorization in `opts`,
+/// if there is one. bcrypt is slow by design, so the check runs on the
+/// blocking thread pool.
+pub(crate) async fn authorize(opts: Arc<ServerOptions>, connect: Connect) -> bool {
+    if opts.authorization.is_none() {
+        return true;
+    }
+    task::spawn_blocking(move || {
+        opts.authorization
+            .as_ref()
+            .is_none_or(|auth| auth.check(&connect))
+    })
+    .await
+    .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(user: Option<&str>, pass: Option<&str>, token: Option<&str>) -> Connect {
+        Connect {
+            user: user.map(String::from),
+            pass: pass.map(String::from),
+            auth_token: token.map(String::from),
+            ..Connect::default()
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let auth = Authorization::Token("s3cr3t".into());
+        assert!(auth.check(&connect(None, None, Some("s3cr3t"))));
+        assert!(!auth.check(&connect(None, None, Some("s3cr3"))));
+        assert!(!auth.check(&connect(Some("s3cr3t"), Some("s3cr3t"), None)));
+
+        let auth = Authorization::Users(vec![
+            User {
+                username: "alice".into(),
+                password: "pass".into(),
+            },
+            User {
+                username: "bob".into(),
+                password: bcrypt::hash("hashed", 4).unwrap(),
+            },
+        ]);
+        assert!(auth.check(&connect(Some("alice"), Some("pass"), None)));
+        assert!(auth.check(&connect(Some("bob"), Some("hashed"), None)));
+        assert!(!auth.check(&connect(Some("bob"), Some("pass"), None)));
+        assert!(!auth.check(&connect(Some("carol"), Some("pass"), None)));
+        assert!(!auth.check(&connect(Some("alice"), None, Some("pass"))));
+    }
+}
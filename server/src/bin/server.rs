@@ -1,14 +1,132 @@
 use tokio::net::TcpListener;
+use rnats::cli::{self, Command};
 use rnats::errors::Error;
-use rnats::server;
+use rnats::server::{self, Control};
 extern crate env_logger;
+use std::future::Future;
 use tokio::signal;
+use tokio::sync::mpsc;
 #[tokio::main]
 pub async fn main() -> Result<(), Error> {
-    env_logger::init();
-    // Bind a TCP listener
-    let port = 1234;
-    let listener = TcpListener::bind(&format!("192.168.1.83:{}", port)).await?;
+    let opts = match cli::parse(std::env::args().skip(1)) {
+        Ok(Command::Run(opts)) => opts,
+        Ok(Command::Help) => {
+            print!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Ok(Command::Version) => {
+            println!("rnats-server: v{}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Ok(Command::TestConfig(opts)) => {
+            if let Some(config_file) = opts.config_file {
+                println!(
+                    "rnats-server: configuration file {} test is successful",
+                    config_file.display()
+                );
+            }
+            return Ok(());
+        }
+        Ok(Command::Signal { signal, pid }) => {
+            if let Err(err) = signal.send(pid) {
+                eprintln!("rnats-server: {}", err);
+                std::process::exit(1);
+            }
+            println!("rnats-server: signal \"{}\" sent to {}", signal, pid);
+            return Ok(());
+        }
+        Err(err @ Error::InvalidFlag(_)) => {
+            eprintln!("rnats-server: {}\n\n{}", err, cli::USAGE);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("rnats-server: {}", err);
+            std::process::exit(1);
+        }
+    };
 
-    server::run(listener, signal::ctrl_c()).await
-}
\ No newline at end of file
+    // -D and -V set the maximum level, which a reload of the configuration
+    // can change. RUST_LOG can narrow what is logged below that level, but
+    // never raise it.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("trace")).init();
+    log::set_max_level(opts.log_level());
+
+    // Install the signal handlers before anything can be signalled.
+    let shutdown = shutdown_signal()?;
+    let (control_tx, control) = mpsc::channel(1);
+    tokio::spawn(forward_signals(control_tx)?);
+
+    let listener = TcpListener::bind((opts.host.as_str(), opts.port)).await?;
+    server::run_with_control(listener, opts, shutdown, control).await
+}
+
+/// Completes on the first of SIGINT, SIGTERM and SIGQUIT, which all shut
+/// the server down gracefully.
+#[cfg(unix)]
+fn shutdown_signal() -> Result<impl Future<Output = ()>, Error> {
+    use signal::unix::{signal, SignalKind};
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut quit = signal(SignalKind::quit())?;
+    Ok(async move {
+        let name = tokio::select! {
+            _ = interrupt.recv() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+            _ = quit.recv() => "SIGQUIT",
+        };
+        log::info!("received {}", name);
+    })
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> Result<impl Future<Output = ()>, Error> {
+    Ok(async {
+        let _ = signal::ctrl_c().await;
+    })
+}
+
+/// Turn the other signals the server handles into requests to the server:
+/// SIGUSR2 enters lame duck mode and SIGHUP reloads the configuration file.
+#[cfg(unix)]
+fn forward_signals(control: mpsc::Sender<Control>) -> Result<impl Future<Output = ()>, Error> {
+    use signal::unix::{signal, SignalKind};
+    let mut lame_duck = signal(SignalKind::user_defined2())?;
+    let mut reload = signal(SignalKind::hangup())?;
+    Ok(async move {
+        loop {
+            let request = tokio::select! {
+                Some(()) = lame_duck.recv() => Control::LameDuck,
+                Some(()) = reload.recv() => match reload_options() {
+                    Some(opts) => Control::Reload(Box::new(opts)),
+                    None => continue,
+                },
+                else => break,
+            };
+            if control.send(request).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn forward_signals(_control: mpsc::Sender<Control>) -> Result<impl Future<Output = ()>, Error> {
+    Ok(async {})
+}
+
+/// Load the options again the way they were at start up, the configuration
+/// file with the flags applied over it.
+#[cfg(unix)]
+fn reload_options() -> Option<rnats::options::ServerOptions> {
+    match cli::parse(std::env::args().skip(1)) {
+        Ok(Command::Run(opts)) if opts.config_file.is_some() => Some(opts),
+        Ok(_) => {
+            log::error!("config reload failed: no configuration file given");
+            None
+        }
+        Err(err) => {
+            log::error!("config reload failed: {}", err);
+            None
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use std::path::PathBuf;
+
+use crate::{
+    auth::{Authorization, User},
+    errors::Error,
+    options::ServerOptions,
+    process::{self, Signal},
+};
+
+/// Usage of `rnats-server`, printed for `--help` and after a bad flag.
+pub const USAGE: &str = "\
+Usage: rnats-server [options]
+
+Server Options:
+    -a, --addr, --net <host>         Bind to host address (default: 0.0.0.0)
+    -p, --port <port>                Use port for clients (default: 4222)
+    -n, --name, --server_name <name> Server name (default: server id)
+    -m, --http_port <port>           Use port for http monitoring
+    -c, --config <file>              Configuration file
+    -t, --test-config                Test configuration and exit
+    -P, --pid <file>                 File to store the process id
+        --ports_file_dir <dir>       Directory to write the ports file to
+                                     (<executable>_<pid>.ports)
+    -sl, --signal <signal>[=<pid>]   Send a signal to a running rnats-server
+                                     (stop, quit, term, reload, ldm), <pid>
+                                     is a process id or a pid file
+
+Authorization Options:
+        --user <user>                User required for connections
+        --pass <password>            Password required for connections
+        --auth <token>               Authorization token required for connections
+
+Logging Options:
+    -D, --debug                      Enable debugging output
+    -V, --trace                      Trace the raw protocol
+    -DV                              Debug and trace
+
+Common Options:
+    -h, --help                       Show this message
+    -v, --version                    Show version
+";
+
+/// What `rnats-server` was asked to do on its command line.
+#[derive(Debug)]
+pub enum Command {
+    /// Run a server with these options.
+    Run(ServerOptions),
+    /// Only check that the configuration file loads.
+    TestConfig(ServerOptions),
+    /// Send `signal` to the server running as process `pid`.
+    Signal { signal: Signal, pid: u32 },
+    /// Print the usage and exit.
+    Help,
+    /// Print the version and exit.
+    Version,
+}
+
+/// Options given as flags. Only the flags that were present are set, so
+/// they can be applied over the configuration file, which they override.
+#[derive(Debug, Default)]
+struct Flags {
+    host: Option<String>,
+    port: Option<u16>,
+    server_name: Option<String>,
+    http_port: Option<u16>,
+    config_file: Option<PathBuf>,
+    pid_file: Option<PathBuf>,
+    ports_file_dir: Option<PathBuf>,
+    debug: Option<bool>,
+    trace: Option<bool>,
+    user: Option<String>,
+    pass: Option<String>,
+    token: Option<String>,
+    test_config: bool,
+    /// The signal to send and, if given, the process id or pid file.
+    signal: Option<(Signal, Option<String>)>,
+}
+
+impl Flags {
+    fn apply(self, opts: &mut ServerOptions) {
+        if let Some(host) = self.host {
+            opts.host = host;
+        }
+        if let Some(port) = self.port {
+            opts.port = port;
+        }
+        if let Some(server_name) = self.server_name {
+            opts.server_name = server_name;
+        }
+        if self.http_port.is_some() {
+            opts.http_port = self.http_port;
+        }
+        if self.pid_file.is_some() {
+            opts.pid_file = self.pid_file;
+        }
+        if self.ports_file_dir.is_some() {
+            opts.ports_file_dir = self.ports_file_dir;
+        }
+        if let Some(debug) = self.debug {
+            opts.debug = debug;
+        }
+        if let Some(trace) = self.trace {
+            opts.trace = trace;
+        }
+        if let Some(token) = self.token {
+            opts.authorization = Some(Authorization::Token(token));
+        }
+        if let (Some(username), Some(password)) = (self.user, self.pass) {
+            opts.authorization = Some(Authorization::Users(vec![User { username, password }]));
+        }
+    }
+}
+
+/// Parse the arguments of `rnats-server`, not including the program name.
+///
+/// Flags follow `nats-server`: they start with `-` or `--`, and take their
+/// value either after `=` or as the next argument.
+pub fn parse<I, S>(args: I) -> Result<Command, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let mut args = args.into_iter().map(Into::into);
+    let mut flags = Flags::default();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')) {
+            Some(flag) if !flag.is_empty() && !flag.starts_with('-') => flag,
+            _ => return Err(invalid(format!("unexpected argument: {}", arg))),
+        };
+        let (name, inline) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (flag, None),
+        };
+        match name {
+            "h" | "help" => return Ok(Command::Help),
+            "v" | "version" => return Ok(Command::Version),
+            "D" | "debug" => flags.debug = Some(switch(name, inline)?),
+            "V" | "trace" => flags.trace = Some(switch(name, inline)?),
+            "DV" => {
+                let on = switch(name, inline)?;
+                flags.debug = Some(on);
+                flags.trace = Some(on);
+            }
+            "a" | "addr" | "net" => flags.host = Some(value(name, inline, &mut args)?),
+            "p" | "port" => flags.port = Some(port(name, value(name, inline, &mut args)?)?),
+            "n" | "name" | "server_name" => {
+                flags.server_name = Some(value(name, inline, &mut args)?)
+            }
+            "m" | "http_port" => {
+                flags.http_port = Some(port(name, value(name, inline, &mut args)?)?)
+            }
+            "c" | "config" => flags.config_file = Some(value(name, inline, &mut args)?.into()),
+            "t" | "test-config" => flags.test_config = switch(name, inline)?,
+            "user" => flags.user = Some(value(name, inline, &mut args)?),
+            "pass" => flags.pass = Some(value(name, inline, &mut args)?),
+            "auth" => flags.token = Some(value(name, inline, &mut args)?),
+            "P" | "pid" => flags.pid_file = Some(value(name, inline, &mut args)?.into()),
+            "ports_file_dir" => flags.ports_file_dir = Some(value(name, inline, &mut args)?.into()),
+            "sl" | "signal" => {
+                let value = value(name, inline, &mut args)?;
+                let (signal, pid) = match value.split_once('=') {
+                    Some((signal, pid)) => (signal, Some(pid.to_string())),
+                    None => (value.as_str(), None),
+                };
+                flags.signal = Some((signal.parse()?, pid));
+            }
+            _ => return Err(invalid(format!("flag provided but not defined: -{}", name))),
+        }
+    }
+
+    if flags.user.is_some() != flags.pass.is_some() {
+        return Err(invalid("--user and --pass must be given together".into()));
+    }
+    if flags.token.is_some() && flags.user.is_some() {
+        return Err(invalid("--auth cannot be used with --user".into()));
+    }
+
+    let mut opts = match &flags.config_file {
+        Some(config_file) => ServerOptions::from_file(config_file)?,
+        None if flags.test_config => {
+            return Err(invalid(
+                "-t needs a configuration file, given with -c".into(),
+            ))
+        }
+        None => ServerOptions::default(),
+    };
+    let test_config = flags.test_config;
+    let signal = flags.signal.take();
+    flags.apply(&mut opts);
+    if let Some((signal, pid)) = signal {
+        let pid = match pid {
+            Some(pid) => match pid.parse() {
+                Ok(pid) => pid,
+                Err(_) => process::read_pid_file(pid.as_ref())?,
+            },
+            None => match &opts.pid_file {
+                Some(pid_file) => process::read_pid_file(pid_file)?,
+                None => {
+                    return Err(invalid(
+                        "-sl needs a process id, given as <signal>=<pid> or with a pid file".into(),
+                    ))
+                }
+            },
+        };
+        return Ok(Command::Signal { signal, pid });
+    }
+    if test_config {
+        return Ok(Command::TestConfig(opts));
+    }
+    Ok(Command::Run(opts))
+}
+
+fn invalid(reason: String) -> Error {
+    Error::InvalidFlag(reason)
+}
+
+/// The value of flag `name`, given inline or as the next argument.
+fn value(
+    name: &str,
+    inline: Option<String>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<String, Error> {
+    inline
+        .or_else(|| args.next())
+        .ok_or_else(|| invalid(format!("flag needs an argument: -{}", name)))
+}
+
+/// A boolean flag is turned on by its presence, or set with `-flag=false`.
+fn switch(name: &str, inline: Option<String>) -> Result<bool, Error> {
+    match inline.as_deref() {
+        None | Some("1" | "t" | "T" | "true" | "TRUE" | "True") => Ok(true),
+        Some("0" | "f" | "F" | "false" | "FALSE" | "False") => Ok(false),
+        Some(value) => Err(invalid(format!(
+            "invalid boolean value {:?} for -{}",
+            value, name
+        ))),
+    }
+}
+
+fn port(name: &str, value: String) -> Result<u16, Error> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("invalid port {:?} for -{}", value, name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn run(args: &[&str]) -> ServerOptions {
+        match parse(args.iter().copied()).unwrap() {
+            Command::Run(opts) => opts,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let opts = run(&[]);
+        assert_eq!(opts.host, "0.0.0.0");
+        assert_eq!(opts.port, 4222);
+        assert!(!opts.debug && !opts.trace);
+
+        let opts = run(&[
+            "-a",
+            "127.0.0.1",
+            "--port=4333",
+            "-n",
+            "n1",
+            "-m",
+            "8222",
+            "-DV",
+        ]);
+        assert_eq!(opts.host, "127.0.0.1");
+        assert_eq!(opts.port, 4333);
+        assert_eq!(opts.server_name, "n1");
+        assert_eq!(opts.http_port, Some(8222));
+        assert!(opts.debug && opts.trace);
+
+        let opts = run(&["--net", "::1", "-p", "0", "-D", "--trace=false"]);
+        assert_eq!(opts.host, "::1");
+        assert_eq!(opts.port, 0);
+        assert!(opts.debug && !opts.trace);
+
+        let opts = run(&["--auth", "s3cr3t"]);
+        assert_eq!(
+            opts.authorization,
+            Some(Authorization::Token("s3cr3t".into()))
+        );
+        let opts = run(&["--user", "alice", "--pass=pass"]);
+        assert_eq!(
+            opts.authorization,
+            Some(Authorization::Users(vec![User {
+                username: "alice".into(),
+                password: "pass".into()
+            }]))
+        );
+
+        assert!(matches!(parse(["-h"]), Ok(Command::Help)));
+        assert!(matches!(parse(["--version"]), Ok(Command::Version)));
+    }
+
+    #[test]
+    fn test_parse_config_file() {
+        let path = std::env::temp_dir().join(format!("rnats-cli-{}.conf", std::process::id()));
+        std::fs::write(&path, "port: 4333\nserver_name: conf\nmax_payload: 64KB\n").unwrap();
+        let file = path.to_str().unwrap();
+
+        // Flags override the file.
+        let opts = run(&["-c", file, "-n", "flag"]);
+        assert_eq!(opts.port, 4333);
+        assert_eq!(opts.server_name, "flag");
+        assert_eq!(opts.max_payload, 64 * 1024);
+        assert_eq!(opts.config_file.as_deref(), Some(path.as_path()));
+
+        assert!(matches!(
+            parse(["--test-config", "-c", file]),
+            Ok(Command::TestConfig(_))
+        ));
+        std::fs::write(&path, "port: 4333\nmax_payload: lots\n").unwrap();
+        match parse(["-t", "-c", file]) {
+            Err(Error::ConfigError(reason)) => {
+                assert_eq!(reason, format!("{}:2:14: invalid size", file))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert!(matches!(
+            parse(["-sl", "reload=42"]),
+            Ok(Command::Signal {
+                signal: Signal::Reload,
+                pid: 42
+            })
+        ));
+
+        let path = std::env::temp_dir().join(format!("rnats-cli-{}.pid", std::process::id()));
+        std::fs::write(&path, "43\n").unwrap();
+        let file = path.to_str().unwrap();
+        assert!(matches!(
+            parse(["--signal", &format!("ldm={}", file)]),
+            Ok(Command::Signal {
+                signal: Signal::LameDuck,
+                pid: 43
+            })
+        ));
+        // Without a pid, the pid file of the options is used.
+        assert!(matches!(
+            parse(["-P", file, "-sl", "quit"]),
+            Ok(Command::Signal {
+                signal: Signal::Quit,
+                pid: 43
+            })
+        ));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            parse(["-P", file, "-sl", "quit"]),
+            Err(Error::SignalError(_))
+        ));
+
+        let opts = run(&["-P", file, "--ports_file_dir=/tmp"]);
+        assert_eq!(opts.pid_file.as_deref(), Some(path.as_path()));
+        assert_eq!(opts.ports_file_dir.as_deref(), Some(Path::new("/tmp")));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for args in [
+            &["-x"][..],
+            &["-p"],
+            &["-p", "http"],
+            &["-p", "70000"],
+            &["-D=maybe"],
+            &["4222"],
+            &["---port", "4222"],
+            &["-t"],
+            &["-sl", "restart=1"],
+            &["--signal", "stop"],
+            &["--user", "alice"],
+            &["--auth", "s3cr3t", "--user", "alice", "--pass", "pass"],
+        ] {
+            assert!(
+                matches!(parse(args.iter().copied()), Err(Error::InvalidFlag(_))),
+                "{:?}",
+                args
+            );
+        }
+    }
+}
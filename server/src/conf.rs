@@ -0,0 +1,712 @@
+//! Parser for the configuration file format of `nats-server`.
+//!
+//! A file is a map of keys to values. Keys are separated from their value by
+//! `:`, `=` or whitespace, and entries by newlines, `,` or `;`. Values are
+//! booleans, numbers, strings, arrays in `[]` and maps in `{}`. Comments
+//! start with `#` or `//`. `include <path>` reads the entries of another
+//! file, and `$NAME` stands for the value of a key defined earlier in the
+//! same or an enclosing map, or else of the environment variable `NAME`.
+
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::errors::Error;
+
+/// Includes nested deeper than this are assumed to include themselves.
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// Where a value was read, for error messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pos {
+    /// The file, `None` when parsing a string.
+    pub file: Option<Arc<PathBuf>>,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A value along with where it was read.
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub value: Value,
+    pub pos: Pos,
+    /// Whether the item was referenced as a variable.
+    used: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Item>),
+    Map(Map),
+}
+
+/// The entries of a map, in the order they were read. A key defined twice
+/// keeps the last value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Map {
+    entries: Vec<(String, Item)>,
+}
+
+/// Parse the configuration in `src`. Included files are looked up relative
+/// to the current directory.
+pub fn parse(src: &str) -> Result<Map, Error> {
+    Parser::new(src, None, 0).parse()
+}
+
+/// Parse the configuration file at `path`. Included files are looked up
+/// relative to its directory.
+pub fn parse_file(path: &Path) -> Result<Map, Error> {
+    let src = fs::read_to_string(path)
+        .map_err(|err| Error::ConfigError(format!("cannot read {}: {}", path.display(), err)))?;
+    Parser::new(&src, Some(path), 0).parse()
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl PartialEq for Item {
+    fn eq(&self, other: &Item) -> bool {
+        self.value == other.value
+    }
+}
+
+impl Item {
+    /// An error about this value, pointing at where it was read.
+    pub fn error(&self, reason: impl fmt::Display) -> Error {
+        error(&self.pos, reason)
+    }
+
+    /// Returns `true` if the item was referenced as a variable.
+    pub fn is_used(&self) -> bool {
+        self.used
+    }
+
+    pub fn as_bool(&self) -> Result<bool, Error> {
+        match &self.value {
+            Value::Bool(b) => Ok(*b),
+            _ => Err(self.error("expected a boolean")),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, Error> {
+        match &self.value {
+            Value::String(s) => Ok(s),
+            _ => Err(self.error("expected a string")),
+        }
+    }
+
+    pub fn as_map(&self) -> Result<&Map, Error> {
+        match &self.value {
+            Value::Map(map) => Ok(map),
+            _ => Err(self.error("expected a map")),
+        }
+    }
+
+    pub fn as_array(&self) -> Result<&[Item], Error> {
+        match &self.value {
+            Value::Array(items) => Ok(items),
+            _ => Err(self.error("expected an array")),
+        }
+    }
+
+    /// A count or a limit, which cannot be negative.
+    pub fn as_usize(&self) -> Result<usize, Error> {
+        match self.value {
+            Value::Integer(n) if n >= 0 => Ok(n as usize),
+            _ => Err(self.error("expected a positive integer")),
+        }
+    }
+
+    pub fn as_port(&self) -> Result<u16, Error> {
+        match self.value {
+            Value::Integer(n) if (0..=u16::MAX as i64).contains(&n) => Ok(n as u16),
+            _ => Err(self.error("expected a port number")),
+        }
+    }
+
+    /// A number of bytes, either an integer or a number with a suffix such
+    /// as `1KB` (1024) or `1K` (1000).
+    pub fn as_size(&self) -> Result<usize, Error> {
+        match &self.value {
+            Value::Integer(n) if *n >= 0 => Ok(*n as usize),
+            Value::String(s) => parse_size(s).ok_or_else(|| self.error("invalid size")),
+            _ => Err(self.error("expected a size")),
+        }
+    }
+
+    /// A number of seconds, or a string such as `2m` or `1h30m`.
+    pub fn as_duration(&self) -> Result<Duration, Error> {
+        match &self.value {
+            Value::Integer(n) if *n >= 0 => Ok(Duration::from_secs(*n as u64)),
+            Value::Float(f) if *f >= 0.0 => Ok(Duration::from_secs_f64(*f)),
+            Value::String(s) => parse_duration(s).ok_or_else(|| self.error("invalid duration")),
+            _ => Err(self.error("expected a duration")),
+        }
+    }
+}
+
+impl Map {
+    /// Returns the value of `key`. Keys are case sensitive.
+    pub fn get(&self, key: &str) -> Option<&Item> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, item)| item)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Item)> {
+        self.entries.iter().map(|(key, item)| (key.as_str(), item))
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Item> {
+        self.entries
+            .iter_mut()
+            .find(|(k, _)| k == key)
+            .map(|(_, item)| item)
+    }
+
+    fn insert(&mut self, key: String, item: Item) {
+        match self.get_mut(&key) {
+            Some(old) => *old = item,
+            None => self.entries.push((key, item)),
+        }
+    }
+}
+
+fn error(pos: &Pos, reason: impl fmt::Display) -> Error {
+    Error::ConfigError(format!("{}: {}", pos, reason))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    i: usize,
+    line: usize,
+    column: usize,
+    file: Option<Arc<PathBuf>>,
+    /// Number of includes this file is nested in.
+    depth: usize,
+}
+
+impl Parser {
+    fn new(src: &str, file: Option<&Path>, depth: usize) -> Parser {
+        Parser {
+            chars: src.chars().collect(),
+            i: 0,
+            line: 1,
+            column: 1,
+            file: file.map(|file| Arc::new(file.to_path_buf())),
+            depth,
+        }
+    }
+
+    fn parse(&mut self) -> Result<Map, Error> {
+        let mut scopes = vec![Map::default()];
+        self.parse_entries(&mut scopes, None)?;
+        Ok(scopes.pop().unwrap_or_default())
+    }
+
+    fn pos(&self) -> Pos {
+        Pos {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.i).copied()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.chars.get(self.i + n).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.i += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn is_comment(&self) -> bool {
+        self.peek() == Some('#') || (self.peek() == Some('/') && self.peek_at(1) == Some('/'))
+    }
+
+    /// Skip spaces and a trailing comment, stopping at the end of the line.
+    fn skip_blank(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\r')) {
+            self.bump();
+        }
+        if self.is_comment() {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip blanks, comments and newlines, and `,` or `;` separators if
+    /// `separators` is set.
+    fn skip_lines(&mut self, separators: bool) {
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                Some('\n') => {}
+                Some(',' | ';') if separators => {}
+                _ => return,
+            }
+            self.bump();
+        }
+    }
+
+    /// Parse entries into the innermost scope until `close`, or the end of
+    /// the file if it is `None`.
+    fn parse_entries(&mut self, scopes: &mut Vec<Map>, close: Option<char>) -> Result<(), Error> {
+        loop {
+            self.skip_lines(true);
+            match self.peek() {
+                None => {
+                    return match close {
+                        Some(close) => Err(error(&self.pos(), format!("expected '{}'", close))),
+                        None => Ok(()),
+                    }
+                }
+                Some(c) if Some(c) == close => {
+                    self.bump();
+                    return Ok(());
+                }
+                Some(_) => self.parse_entry(scopes, close)?,
+            }
+        }
+    }
+
+    fn parse_entry(&mut self, scopes: &mut Vec<Map>, close: Option<char>) -> Result<(), Error> {
+        let pos = self.pos();
+        let quoted = matches!(self.peek(), Some('"' | '\''));
+        let key = match self.peek() {
+            Some('"') => self.parse_quoted()?,
+            Some('\'') => self.parse_raw()?,
+            _ => self.take_while(|c| !is_key_end(c)),
+        };
+        if key.is_empty() {
+            let found = self.peek().unwrap_or(' ');
+            return Err(error(&pos, format!("expected a key, found '{}'", found)));
+        }
+        self.skip_blank();
+
+        if key == "include" && !quoted {
+            let path = self.parse_value(scopes)?;
+            self.end_entry(close)?;
+            return self.include(scopes, &path);
+        }
+
+        if matches!(self.peek(), Some(':' | '=')) {
+            self.bump();
+            self.skip_blank();
+        }
+        let item = self.parse_value(scopes)?;
+        self.end_entry(close)?;
+        if let Some(scope) = scopes.last_mut() {
+            scope.insert(key, item);
+        }
+        Ok(())
+    }
+
+    /// After a value, only a comment, a separator or the end of the
+    /// enclosing map may follow on the same line.
+    fn end_entry(&mut self, close: Option<char>) -> Result<(), Error> {
+        self.skip_blank();
+        match self.peek() {
+            None | Some('\n' | ',' | ';') => Ok(()),
+            Some(c) if Some(c) == close => Ok(()),
+            Some(c) => Err(error(
+                &self.pos(),
+                format!("unexpected '{}' after value", c),
+            )),
+        }
+    }
+
+    fn parse_value(&mut self, scopes: &mut Vec<Map>) -> Result<Item, Error> {
+        let pos = self.pos();
+        let value = match self.peek() {
+            None | Some('\n' | ',' | ';' | '}' | ']') => {
+                return Err(error(&pos, "expected a value"));
+            }
+            Some('{') => {
+                self.bump();
+                scopes.push(Map::default());
+                let res = self.parse_entries(scopes, Some('}'));
+                let map = scopes.pop().unwrap_or_default();
+                res?;
+                Value::Map(map)
+            }
+            Some('[') => {
+                self.bump();
+                Value::Array(self.parse_array(scopes)?)
+            }
+            Some('"') => Value::String(self.parse_quoted()?),
+            Some('\'') => Value::String(self.parse_raw()?),
+            Some(_) => {
+                let text = self.take_while(|c| !is_value_end(c));
+                if let Some(name) = text.strip_prefix('$') {
+                    return variable(scopes, name, pos);
+                }
+                scalar(&text)
+            }
+        };
+        Ok(Item {
+            value,
+            pos,
+            used: false,
+        })
+    }
+
+    fn parse_array(&mut self, scopes: &mut Vec<Map>) -> Result<Vec<Item>, Error> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_lines(true);
+            match self.peek() {
+                None => return Err(error(&self.pos(), "expected ']'")),
+                Some(']') => {
+                    self.bump();
+                    return Ok(items);
+                }
+                Some(_) => {
+                    items.push(self.parse_value(scopes)?);
+                    self.end_entry(Some(']'))?;
+                }
+            }
+        }
+    }
+
+    /// A string in double quotes, with escapes.
+    fn parse_quoted(&mut self) -> Result<String, Error> {
+        let pos = self.pos();
+        self.bump();
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(error(&pos, "unterminated string")),
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let escape = self.pos();
+                    let c = match self.bump() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('u') => {
+                            let hex: String = (0..4).filter_map(|_| self.bump()).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| error(&escape, "invalid unicode escape"))?
+                        }
+                        _ => return Err(error(&escape, "invalid escape")),
+                    };
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    /// A string in single quotes, taken as is.
+    fn parse_raw(&mut self) -> Result<String, Error> {
+        let pos = self.pos();
+        self.bump();
+        let s = self.take_while(|c| c != '\'' && c != '\n');
+        match self.bump() {
+            Some('\'') => Ok(s),
+            _ => Err(error(&pos, "unterminated string")),
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let mut s = String::new();
+        while let Some(c) = self.peek().filter(|&c| f(c)) {
+            s.push(c);
+            self.bump();
+        }
+        s
+    }
+
+    /// Read the entries of the file at `path` into the current scope.
+    fn include(&mut self, scopes: &mut Vec<Map>, path: &Item) -> Result<(), Error> {
+        let name = path.as_str()?;
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            return Err(path.error(format!("includes nested too deep at {}", name)));
+        }
+        let dir = match &self.file {
+            Some(file) => file.parent().unwrap_or_else(|| Path::new("")),
+            None => Path::new(""),
+        };
+        let file = dir.join(name);
+        let src = fs::read_to_string(&file)
+            .map_err(|err| path.error(format!("cannot include {}: {}", file.display(), err)))?;
+        Parser::new(&src, Some(&file), self.depth + 1).parse_entries(scopes, None)
+    }
+}
+
+/// Resolve `$name` to the value of a key in the innermost scope defining it,
+/// or to the environment variable `name`.
+fn variable(scopes: &mut [Map], name: &str, pos: Pos) -> Result<Item, Error> {
+    for scope in scopes.iter_mut().rev() {
+        if let Some(item) = scope.get_mut(name) {
+            item.used = true;
+            return Ok(Item {
+                value: item.value.clone(),
+                pos,
+                used: false,
+            });
+        }
+    }
+    match env::var(name) {
+        Ok(value) => Ok(Item {
+            value: scalar(&value),
+            pos,
+            used: false,
+        }),
+        Err(_) => Err(error(
+            &pos,
+            format!("variable reference for '{}' can not be found", name),
+        )),
+    }
+}
+
+/// The value of an unquoted word.
+fn scalar(text: &str) -> Value {
+    match text.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" => return Value::Bool(true),
+        "false" | "no" | "off" => return Value::Bool(false),
+        _ => {}
+    }
+    if let Ok(n) = text.parse() {
+        return Value::Integer(n);
+    }
+    if text.contains('.') {
+        if let Ok(f) = text.parse() {
+            return Value::Float(f);
+        }
+    }
+    Value::String(text.to_string())
+}
+
+fn is_key_end(c: char) -> bool {
+    c.is_whitespace() || matches!(c, ':' | '=' | '{' | '[' | '#' | ',' | ';' | '}' | ']')
+}
+
+fn is_value_end(c: char) -> bool {
+    c.is_whitespace() || matches!(c, ',' | ';' | '}' | ']')
+}
+
+/// Parse a size such as `1MB`. Suffixes ending in `b` or `i` are powers of
+/// 1024, single letters are powers of 1000.
+fn parse_size(s: &str) -> Option<usize> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (n, suffix) = s.split_at(split);
+    let n: usize = n.parse().ok()?;
+    let scale: usize = match suffix.to_ascii_lowercase().as_str() {
+        "k" => 1000,
+        "kb" | "ki" | "kib" => 1 << 10,
+        "m" => 1000 * 1000,
+        "mb" | "mi" | "mib" => 1 << 20,
+        "g" => 1000 * 1000 * 1000,
+        "gb" | "gi" | "gib" => 1 << 30,
+        _ => return None,
+    };
+    n.checked_mul(scale)
+}
+
+/// Parse a duration such as `2m`, `1h30m` or `250ms`.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let mut rest = s;
+    let mut total = Duration::ZERO;
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let n: f64 = rest[..split].parse().ok()?;
+        rest = &rest[split..];
+        let end = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let unit = match &rest[..end] {
+            "ns" => 1e-9,
+            "us" | "µs" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return None,
+        };
+        total += Duration::try_from_secs_f64(n * unit).ok()?;
+        rest = &rest[end..];
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(map: &Map, key: &str) -> Value {
+        map.get(key).unwrap().value.clone()
+    }
+
+    fn err(src: &str) -> String {
+        match parse(src) {
+            Err(Error::ConfigError(reason)) => reason,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let map = parse(
+            "# comment
+            a: 1
+            b = \"two\\n\" // comment
+            c 3.5; d: true, e: off
+            listen: 127.0.0.1:4222
+            url: nats://localhost:4222
+            raw: 'a\\b'
+            size: 1MB
+            list: [1, two
+              3]
+            nested {
+              inner: { x: 1 }
+            }
+            ",
+        )
+        .unwrap();
+        assert_eq!(value(&map, "a"), Value::Integer(1));
+        assert_eq!(value(&map, "b"), Value::String("two\n".into()));
+        assert_eq!(value(&map, "c"), Value::Float(3.5));
+        assert_eq!(value(&map, "d"), Value::Bool(true));
+        assert_eq!(value(&map, "e"), Value::Bool(false));
+        assert_eq!(
+            value(&map, "listen"),
+            Value::String("127.0.0.1:4222".into())
+        );
+        assert_eq!(
+            value(&map, "url"),
+            Value::String("nats://localhost:4222".into())
+        );
+        assert_eq!(value(&map, "raw"), Value::String("a\\b".into()));
+        assert_eq!(map.get("size").unwrap().as_size().unwrap(), 1024 * 1024);
+        let list = map.get("list").unwrap();
+        let list: Vec<_> = list.as_array().unwrap().iter().map(|i| &i.value).collect();
+        assert_eq!(
+            list,
+            [
+                &Value::Integer(1),
+                &Value::String("two".into()),
+                &Value::Integer(3)
+            ]
+        );
+        let nested = map.get("nested").unwrap().as_map().unwrap();
+        let inner = nested.get("inner").unwrap().as_map().unwrap();
+        assert_eq!(value(inner, "x"), Value::Integer(1));
+        assert_eq!(map.get("nested").unwrap().pos.line, 11);
+    }
+
+    #[test]
+    fn test_variables() {
+        env::set_var("RNATS_CONF_TEST_PORT", "4333");
+        let map = parse(
+            "port: 4222
+            name: srv
+            nested { port: 1, a: $port }
+            b: $port
+            c: $name
+            env: $RNATS_CONF_TEST_PORT",
+        )
+        .unwrap();
+        let nested = map.get("nested").unwrap().as_map().unwrap();
+        assert_eq!(value(nested, "a"), Value::Integer(1));
+        assert_eq!(value(&map, "b"), Value::Integer(4222));
+        assert_eq!(value(&map, "c"), Value::String("srv".into()));
+        assert_eq!(value(&map, "env"), Value::Integer(4333));
+        assert!(map.get("name").unwrap().is_used());
+        assert!(!map.get("b").unwrap().is_used());
+
+        assert_eq!(
+            err("a: 1\nb: $nope"),
+            "2:4: variable reference for 'nope' can not be found"
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = env::temp_dir().join(format!("rnats-conf-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("limits.conf"), "max_payload: 2MB\nport: $port\n").unwrap();
+        fs::write(dir.join("self.conf"), "include self.conf\n").unwrap();
+        fs::write(dir.join("bad.conf"), "a: 1\nb: [1, 2\n").unwrap();
+        let main = dir.join("main.conf");
+        fs::write(&main, "port: 4333\ninclude ./limits.conf\nhost: x\n").unwrap();
+
+        let map = parse_file(&main).unwrap();
+        assert_eq!(map.get("max_payload").unwrap().as_size().unwrap(), 2 << 20);
+        assert_eq!(value(&map, "port"), Value::Integer(4333));
+        assert_eq!(value(&map, "host"), Value::String("x".into()));
+
+        let err = |name: &str| match parse_file(&dir.join(name)) {
+            Err(Error::ConfigError(reason)) => reason,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(err("self.conf").contains("includes nested too deep"));
+        assert_eq!(
+            err("bad.conf"),
+            format!("{}:3:1: expected ']'", dir.join("bad.conf").display())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(err("a: 1 2"), "1:6: unexpected '2' after value");
+        assert_eq!(err("a {\n  b: 1\n"), "3:1: expected '}'");
+        assert_eq!(err("\n  a: \"open"), "2:6: unterminated string");
+        assert_eq!(err("a:\n"), "1:3: expected a value");
+        assert_eq!(err("= 1"), "1:1: expected a key, found '='");
+    }
+
+    #[test]
+    fn test_sizes_and_durations() {
+        assert_eq!(parse_size("1K"), Some(1000));
+        assert_eq!(parse_size("1kb"), Some(1024));
+        assert_eq!(parse_size("64MB"), Some(64 << 20));
+        assert_eq!(parse_size("1G"), Some(1_000_000_000));
+        assert_eq!(parse_size("MB"), None);
+        assert_eq!(parse_size("1XB"), None);
+
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2"), None);
+        assert_eq!(parse_duration("2w"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+}
@@ -0,0 +1,116 @@
+use log::info;
+use serde::Deserialize;
+use std::fmt;
+
+use crate::{auth, connection::Connection, errors::Error, server::Db};
+
+/// Options sent by the client in its `CONNECT` message.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Connect {
+    /// Acknowledge every protocol message with `+OK`.
+    pub verbose: bool,
+    /// Turn on additional strict format checking.
+    pub pedantic: bool,
+    pub tls_required: bool,
+    /// Deliver messages published by this connection to its own
+    /// subscriptions.
+    pub echo: bool,
+    pub name: Option<String>,
+    pub lang: String,
+    pub version: String,
+    pub protocol: i32,
+    /// The client supports message headers.
+    pub headers: bool,
+    /// The client wants a no-responders status for requests nobody answers.
+    pub no_responders: bool,
+    /// Token, when the server authenticates clients with one.
+    pub auth_token: Option<String>,
+    /// User name and password, when the server authenticates users.
+    pub user: Option<String>,
+    pub pass: Option<String>,
+}
+
+impl Default for Connect {
+    fn default() -> Connect {
+        Connect {
+            verbose: false,
+            pedantic: false,
+            tls_required: false,
+            echo: true,
+            name: None,
+            lang: String::new(),
+            version: String::new(),
+            protocol: 0,
+            headers: false,
+            no_responders: false,
+            auth_token: None,
+            user: None,
+            pass: None,
+        }
+    }
+}
+
+/// Shown in place of the secrets, so traces never hold credentials.
+const REDACTED: &str = "[REDACTED]";
+
+impl fmt::Debug for Connect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connect")
+            .field("verbose", &self.verbose)
+            .field("pedantic", &self.pedantic)
+            .field("tls_required", &self.tls_required)
+            .field("echo", &self.echo)
+            .field("name", &self.name)
+            .field("lang", &self.lang)
+            .field("version", &self.version)
+            .field("protocol", &self.protocol)
+            .field("headers", &self.headers)
+            .field("no_responders", &self.no_responders)
+            .field("auth_token", &self.auth_token.as_ref().map(|_| REDACTED))
+            .field("user", &self.user)
+            .field("pass", &self.pass.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+impl Connect {
+    /// Check the client's credentials, if the server requires them, and
+    /// store its options on the connection.
+    pub(crate) async fn apply(self, db: &Db, dst: &mut Connection) -> Result<(), Error> {
+        if !auth::authorize(db.options(), self.clone()).await {
+            info!(
+                "authorization failed name:{:?} user:{:?}",
+                self.name, self.user
+            );
+            return Err(Error::AuthorizationViolation);
+        }
+        info!(
+            "client connected name:{:?} lang:{} version:{}",
+            self.name, self.lang, self.version
+        );
+        dst.opts = self;
+        dst.connected = true;
+        dst.ok().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_redacts_secrets() {
+        let connect = Connect {
+            user: Some("alice".into()),
+            pass: Some("s3cr3t".into()),
+            auth_token: Some("t0k3n".into()),
+            ..Connect::default()
+        };
+        let debug = format!("{:?}", connect);
+        assert!(debug.contains("\"alice\""));
+        assert!(!debug.contains("s3cr3t"));
+        assert!(!debug.contains("t0k3n"));
+        assert!(debug.contains("pass: Some(\"[REDACTED]\")"));
+    }
+}
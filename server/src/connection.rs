@@ -1,17 +1,147 @@
-use tokio::net::TcpStream;
-use tokio_util::codec::Framed;
+use log::info;
+use tokio::{
+    net::{tcp::OwnedReadHalf, TcpStream},
+    time::{self, Instant, Interval},
+};
+use tokio_util::codec::FramedRead;
 
-use crate::protocol::NatsMessageCodec;
+use crate::{
+    connect::Connect,
+    errors::Error,
+    options::ServerOptions,
+    outbound::{Outbound, Writer},
+    protocol::{NatsMessageCodec, ServerOp},
+    server::Db,
+    subscribe::Subscriptions,
+};
 
+/// A client connection.
+///
+/// The socket is split in two: operations are read from `reader`, while
+/// everything sent to the client goes through the `outbound` queue to a
+/// writer task that owns the write half.
 #[derive(Debug)]
 pub struct Connection {
-    pub stream: Framed<TcpStream, NatsMessageCodec>,
+    /// Id the server gave the client when it was accepted.
+    pub(crate) cid: u64,
+    pub reader: FramedRead<OwnedReadHalf, NatsMessageCodec>,
+    pub(crate) outbound: Outbound,
+    writer: Option<Writer>,
+    /// Options the client sent in `CONNECT`.
+    pub opts: Connect,
+    /// Whether the client sent a `CONNECT` that was accepted.
+    pub(crate) connected: bool,
+    /// Fires every `ping_interval` to send a keep-alive `PING`.
+    pub ping_timer: Interval,
+    /// Number of `PING`s sent that have not been answered yet.
+    pub pings_out: usize,
+    max_pings_out: usize,
+    /// The client's subscriptions.
+    pub(crate) subs: Subscriptions,
 }
 
 impl Connection {
-    pub fn new(socket: TcpStream) -> Connection {
+    pub(crate) fn new(socket: TcpStream, db: &Db) -> Connection {
+        let opts = db.options();
+        let start = Instant::now() + opts.ping_interval;
+        let (read_half, write_half) = socket.into_split();
+        let (outbound, rx) = Outbound::channel(opts.max_pending_msgs, opts.max_pending);
+        let cid = db.next_client_id();
         Connection {
-            stream: Framed::new(socket, NatsMessageCodec::new()),
+            cid,
+            reader: FramedRead::new(
+                read_half,
+                NatsMessageCodec::with_limits(opts.max_payload, opts.max_control_line),
+            ),
+            writer: Some(Writer::spawn(
+                write_half,
+                &outbound,
+                rx,
+                opts.write_deadline,
+            )),
+            opts: Connect::default(),
+            connected: false,
+            ping_timer: time::interval_at(start, opts.ping_interval),
+            pings_out: 0,
+            max_pings_out: opts.max_pings_out,
+            subs: Subscriptions::new(db.clone(), cid, outbound.clone()),
+            outbound,
+        }
+    }
+
+    /// Apply options reloaded while the client is connected: the parser
+    /// limits and the keep-alive settings. The pending limits and the write
+    /// deadline only change for new clients. Returns `true` if the limits
+    /// advertised in `INFO` changed.
+    pub(crate) fn reload(&mut self, opts: &ServerOptions) -> bool {
+        let limits = self
+            .reader
+            .decoder_mut()
+            .set_limits(opts.max_payload, opts.max_control_line);
+        if self.ping_timer.period() != opts.ping_interval {
+            let start = Instant::now() + opts.ping_interval;
+            self.ping_timer = time::interval_at(start, opts.ping_interval);
+        }
+        self.max_pings_out = opts.max_pings_out;
+        limits
+    }
+
+    /// Queue `op` to be written to the client.
+    pub(crate) async fn send(&self, op: ServerOp) -> Result<(), Error> {
+        self.outbound.send(op).await
+    }
+
+    /// Write out everything still queued and close the socket.
+    pub(crate) async fn close(&mut self) -> Result<(), Error> {
+        match self.writer.take() {
+            Some(writer) => writer.close().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Close the connection because of `err`, dropping whatever is still
+    /// queued for the client and only reporting `err`.
+    pub(crate) async fn abort(&mut self, err: &Error) -> Result<(), Error> {
+        let writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        match err.client_reason() {
+            Some(reason) => writer.abort(ServerOp::Err(reason.to_string())).await,
+            None => writer.close().await,
+        }
+    }
+
+    /// Called each time `ping_timer` fires. Sends a `PING`, or fails with
+    /// `StaleConnection` if the client has left too many `PING`s unanswered.
+    pub(crate) async fn ping(&mut self) -> Result<(), Error> {
+        if self.pings_out + 1 > self.max_pings_out {
+            info!("stale connection, {} pings unanswered", self.pings_out);
+            return Err(Error::StaleConnection);
+        }
+        self.pings_out += 1;
+        self.send(ServerOp::Ping).await
+    }
+
+    /// Acknowledge a successful operation with `+OK` if the client asked for
+    /// verbose mode.
+    pub(crate) async fn ok(&mut self) -> Result<(), Error> {
+        if self.opts.verbose {
+            self.send(ServerOp::Ok).await?;
+        }
+        Ok(())
+    }
+
+    /// Report `err` to the client with `-ERR`. Returns `Ok` if the connection
+    /// can keep going, or gives the error back if it must be closed.
+    pub(crate) async fn reply_error(&mut self, err: Error) -> Result<(), Error> {
+        if let Some(reason) = err.client_reason() {
+            self.send(ServerOp::Err(reason.to_string())).await?;
+        }
+        if err.is_fatal() {
+            Err(err)
+        } else {
+            Ok(())
         }
     }
 }
@@ -5,8 +5,36 @@ use std::num::ParseIntError;
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("ProtocolError")]
-    ProtocolError,
+    #[error("ProtocolError: '{op}' at {pos}")]
+    ProtocolError { op: String, pos: usize },
+    #[error("InvalidHeaders")]
+    InvalidHeaders,
+    #[error("InvalidSubject")]
+    InvalidSubject,
+    #[error("MaxPayloadViolation")]
+    MaxPayloadViolation,
+    #[error("MaxControlLineExceeded")]
+    MaxControlLineExceeded,
+    #[error("AuthorizationViolation")]
+    AuthorizationViolation,
+    #[error("AuthenticationTimeout")]
+    AuthenticationTimeout,
+    #[error("HeadersNotSupported")]
+    HeadersNotSupported,
+    #[error("StaleConnection")]
+    StaleConnection,
+    #[error("SlowConsumer")]
+    SlowConsumer,
+    #[error("MaxConnectionsExceeded")]
+    MaxConnectionsExceeded,
+    #[error("InvalidFlag: {0}")]
+    InvalidFlag(String),
+    #[error("ConfigError: {0}")]
+    ConfigError(String),
+    #[error("SignalError: {0}")]
+    SignalError(String),
+    #[error("ConnectionClosed")]
+    ConnectionClosed,
     #[error("IOError: {0}")]
     IOError(#[from] std::io::Error),
     #[error("CodecError: {0}")]
@@ -15,4 +43,39 @@ pub enum Error {
     FromUtf8Error(#[from] FromUtf8Error),
     #[error("ParseIntError: {0}")]
     ParseIntError(#[from] ParseIntError),
-}
\ No newline at end of file
+    #[error("JsonError: {0}")]
+    JsonError(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// The reason reported to the client in `-ERR '<reason>'`, or `None` if
+    /// the error is not reported (e.g. the socket is already gone).
+    pub fn client_reason(&self) -> Option<&'static str> {
+        use Error::*;
+        match self {
+            ProtocolError { .. }
+            | InvalidHeaders
+            | CodecError(_)
+            | FromUtf8Error(_)
+            | ParseIntError(_)
+            | JsonError(_) => Some("Unknown Protocol Operation"),
+            InvalidSubject => Some("Invalid Subject"),
+            MaxPayloadViolation => Some("Maximum Payload Violation"),
+            MaxControlLineExceeded => Some("Maximum Control Line Exceeded"),
+            AuthorizationViolation => Some("Authorization Violation"),
+            AuthenticationTimeout => Some("Authentication Timeout"),
+            HeadersNotSupported => Some("Headers Not Supported"),
+            StaleConnection => Some("Stale Connection"),
+            SlowConsumer => Some("Slow Consumer"),
+            MaxConnectionsExceeded => Some("maximum connections exceeded"),
+            ConnectionClosed | IOError(_) | InvalidFlag(_) | ConfigError(_) | SignalError(_) => {
+                None
+            }
+        }
+    }
+
+    /// Returns `true` if the connection must be closed after this error.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Error::InvalidSubject)
+    }
+}
@@ -0,0 +1,170 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::errors::Error;
+
+/// Version line every header section starts with.
+const VERSION_LINE: &str = "NATS/1.0";
+
+/// Message headers sent with `HPUB` and delivered with `HMSG`.
+///
+/// A header may have several values. Names keep the case they were given
+/// with and are matched exactly.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+    /// Status code on the version line, e.g. `503` for no responders.
+    pub status: Option<u16>,
+    /// Text following the status code on the version line.
+    pub description: Option<String>,
+    entries: Vec<(String, Vec<String>)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Headers carrying only a status line, e.g. `NATS/1.0 503`.
+    pub fn with_status(status: u16, description: Option<&str>) -> Headers {
+        Headers {
+            status: Some(status),
+            description: description.map(|d| d.to_string()),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Set `name` to `value`, replacing any previous values.
+    pub fn insert(&mut self, name: impl ToString, value: impl ToString) {
+        let name = name.to_string();
+        match self.entries.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, values)) => *values = vec![value.to_string()],
+            None => self.entries.push((name, vec![value.to_string()])),
+        }
+    }
+
+    /// Add `value` to the values of `name`.
+    pub fn append(&mut self, name: impl ToString, value: impl ToString) {
+        let name = name.to_string();
+        match self.entries.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, values)) => values.push(value.to_string()),
+            None => self.entries.push((name, vec![value.to_string()])),
+        }
+    }
+
+    /// Returns the first value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).next()
+    }
+
+    /// Returns every value of `name`.
+    pub fn get_all<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .into_iter()
+            .flat_map(|(_, values)| values.iter().map(|v| &v[..]))
+    }
+
+    /// Iterate over `(name, value)` pairs in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .flat_map(|(n, values)| values.iter().map(move |v| (&n[..], &v[..])))
+    }
+
+    /// Parse a header section, including its terminating blank line.
+    pub fn parse(src: &[u8]) -> Result<Headers, Error> {
+        let src = std::str::from_utf8(src)?
+            .strip_suffix("\r\n\r\n")
+            .ok_or(Error::InvalidHeaders)?;
+        let mut lines = src.split("\r\n");
+
+        // NATS/1.0[ <status>[ <description>]]
+        let version = lines.next().ok_or(Error::InvalidHeaders)?;
+        let status_line = version
+            .strip_prefix(VERSION_LINE)
+            .ok_or(Error::InvalidHeaders)?
+            .trim();
+        let mut headers = Headers::new();
+        if !status_line.is_empty() {
+            let mut parts = status_line.splitn(2, ' ');
+            headers.status = parts.next().map(str::parse).transpose()?;
+            headers.description = parts
+                .next()
+                .map(|d| d.trim().to_string())
+                .filter(|d| !d.is_empty());
+        }
+
+        // Name: value
+        for line in lines {
+            let (name, value) = line.split_once(':').ok_or(Error::InvalidHeaders)?;
+            if name.is_empty() {
+                return Err(Error::InvalidHeaders);
+            }
+            headers.append(name, value.trim());
+        }
+        Ok(headers)
+    }
+
+    /// Serialize the header section, including its terminating blank line.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut dst = BytesMut::new();
+        dst.put_slice(VERSION_LINE.as_bytes());
+        if let Some(status) = self.status {
+            dst.put_slice(format!(" {}", status).as_bytes());
+            if let Some(description) = &self.description {
+                dst.put_slice(b" ");
+                dst.put_slice(description.as_bytes());
+            }
+        }
+        dst.put_slice(b"\r\n");
+        for (name, value) in self.iter() {
+            dst.put_slice(name.as_bytes());
+            dst.put_slice(b": ");
+            dst.put_slice(value.as_bytes());
+            dst.put_slice(b"\r\n");
+        }
+        dst.put_slice(b"\r\n");
+        dst.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let headers =
+            Headers::parse(b"NATS/1.0\r\nTrace-Id: abc\r\ntrace-id: def\r\nTrace-Id:  ghi\r\n\r\n")
+                .unwrap();
+        assert_eq!(headers.status, None);
+        assert_eq!(headers.get("Trace-Id"), Some("abc"));
+        assert_eq!(
+            headers.get_all("Trace-Id").collect::<Vec<_>>(),
+            vec!["abc", "ghi"]
+        );
+        assert_eq!(headers.get("trace-id"), Some("def"));
+
+        let headers = Headers::parse(b"NATS/1.0 503 No Responders\r\n\r\n").unwrap();
+        assert_eq!(headers.status, Some(503));
+        assert_eq!(headers.description.as_deref(), Some("No Responders"));
+
+        assert!(Headers::parse(b"HTTP/1.1\r\n\r\n").is_err());
+        assert!(Headers::parse(b"NATS/1.0\r\nbroken\r\n\r\n").is_err());
+        assert!(Headers::parse(b"NATS/1.0\r\n").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut headers = Headers::with_status(408, Some("Request Timeout"));
+        headers.insert("A", "1");
+        headers.append("A", "2");
+        headers.insert("b", "3");
+        let bytes = headers.to_bytes();
+        assert_eq!(
+            &bytes[..],
+            &b"NATS/1.0 408 Request Timeout\r\nA: 1\r\nA: 2\r\nb: 3\r\n\r\n"[..]
+        );
+        assert_eq!(Headers::parse(&bytes).unwrap(), headers);
+    }
+}
@@ -0,0 +1,67 @@
+use rand::{distr::Alphanumeric, RngExt};
+use serde::Serialize;
+
+use crate::options::ServerOptions;
+
+/// Version of the client protocol spoken by this server.
+pub const PROTO: i32 = 1;
+
+/// The `INFO` block sent to every client as soon as its connection is
+/// accepted.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerInfo {
+    pub server_id: String,
+    pub server_name: String,
+    pub version: String,
+    pub proto: i32,
+    pub host: String,
+    pub port: u16,
+    pub headers: bool,
+    pub max_payload: usize,
+    pub max_control_line: usize,
+    pub auth_required: bool,
+    pub tls_required: bool,
+    /// Set when the server is in lame duck mode and about to close its
+    /// clients, so they reconnect elsewhere.
+    #[serde(skip_serializing_if = "is_false")]
+    pub ldm: bool,
+}
+
+impl ServerInfo {
+    /// Create the `INFO` for a server listening on `host:port`, advertising
+    /// the limits in `opts`.
+    pub fn new(host: impl ToString, port: u16, opts: &ServerOptions) -> ServerInfo {
+        let server_id = server_id();
+        let server_name = match opts.server_name.as_str() {
+            "" => server_id.clone(),
+            name => name.to_string(),
+        };
+        ServerInfo {
+            server_id,
+            server_name,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            proto: PROTO,
+            host: host.to_string(),
+            port,
+            headers: true,
+            max_payload: opts.max_payload,
+            max_control_line: opts.max_control_line,
+            auth_required: opts.authorization.is_some(),
+            tls_required: false,
+            ldm: false,
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+/// Generate a random, upper case server id.
+fn server_id() -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(22)
+        .map(|c| (c as char).to_ascii_uppercase())
+        .collect()
+}
@@ -3,9 +3,23 @@ pub mod protocol;
 pub mod errors;
 pub mod server;
 pub mod connection;
+pub mod outbound;
 pub mod shutdown;
 pub mod subscribe;
+pub mod sublist;
+pub mod subject;
+pub mod unsubscribe;
 pub mod publish;
+pub mod headers;
+pub mod info;
+pub mod connect;
+pub mod ping;
+pub mod options;
+pub mod stats;
+pub mod cli;
+pub mod conf;
+pub mod process;
+pub mod auth;
 
 
 // fn main() {
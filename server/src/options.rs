@@ -0,0 +1,444 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::LevelFilter;
+
+use crate::{
+    auth::{Authorization, User},
+    conf::{self, Item, Map},
+    errors::Error,
+};
+
+/// Default interface the server listens on, all of them.
+pub const DEFAULT_HOST: &str = "0.0.0.0";
+
+/// Default port clients connect to.
+pub const DEFAULT_PORT: u16 = 4222;
+
+/// Default maximum number of clients connected at the same time.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64 * 1024;
+
+/// Default time lame duck mode takes to close every client.
+pub const DEFAULT_LAME_DUCK_DURATION: Duration = Duration::from_secs(2 * 60);
+
+/// Default time lame duck mode waits before it starts closing clients.
+pub const DEFAULT_LAME_DUCK_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Default time a write to a client may take before the client is closed as
+/// a slow consumer.
+pub const DEFAULT_WRITE_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Default time a client has to authenticate with `CONNECT` before it is
+/// closed, when the server requires authentication.
+pub const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Default interval between `PING`s sent by the server to each client.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// Default number of unanswered `PING`s before a connection is considered
+/// stale and closed.
+pub const DEFAULT_MAX_PINGS_OUT: usize = 2;
+
+/// Default maximum number of bytes accepted in a single message payload.
+pub const DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+
+/// Default maximum length of a protocol line, not counting payloads.
+pub const DEFAULT_MAX_CONTROL_LINE: usize = 4096;
+
+/// Default maximum number of message bytes waiting to be written to a
+/// client before it is considered a slow consumer.
+pub const DEFAULT_MAX_PENDING: usize = 64 * 1024 * 1024;
+
+/// Default maximum number of operations waiting to be written to a client
+/// before it is considered a slow consumer.
+pub const DEFAULT_MAX_PENDING_MSGS: usize = 64 * 1024;
+
+/// Options that only take effect when the server starts, a reload cannot
+/// change them.
+const NOT_RELOADABLE: &[&str] = &[
+    "host",
+    "port",
+    "server_name",
+    "http_port",
+    "pid_file",
+    "ports_file_dir",
+];
+
+/// Settings of the server: where it listens and how it treats its clients.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Interface to listen on.
+    pub host: String,
+    /// Port to listen on.
+    pub port: u16,
+    /// Name of the server, advertised in `INFO`. The server id is used when
+    /// it is empty.
+    pub server_name: String,
+    /// Port of the HTTP monitoring endpoint, if one was asked for.
+    pub http_port: Option<u16>,
+    /// Configuration file the options were loaded from.
+    pub config_file: Option<PathBuf>,
+    /// File the server writes its process id to.
+    pub pid_file: Option<PathBuf>,
+    /// Directory the server writes its ports file to, named
+    /// `<executable>_<pid>.ports` and listing the URLs clients connect to.
+    pub ports_file_dir: Option<PathBuf>,
+    /// Log debug messages.
+    pub debug: bool,
+    /// Log every protocol operation.
+    pub trace: bool,
+    /// Maximum number of clients connected at the same time.
+    pub max_connections: usize,
+    /// How clients authenticate, `None` if they do not have to.
+    pub authorization: Option<Authorization>,
+    /// Time a client has to authenticate before it is closed.
+    pub auth_timeout: Duration,
+    /// Time a write to a client may take before the client is closed as a
+    /// slow consumer.
+    pub write_deadline: Duration,
+    /// Time lame duck mode takes to close every client, grace period
+    /// included.
+    pub lame_duck_duration: Duration,
+    /// Time clients are given to reconnect elsewhere after entering lame
+    /// duck mode, before the server starts closing them.
+    pub lame_duck_grace_period: Duration,
+    /// How often the server pings each client.
+    pub ping_interval: Duration,
+    /// Maximum number of outstanding `PING`s before the connection is closed.
+    pub max_pings_out: usize,
+    /// Largest payload a client may publish. Advertised in `INFO`.
+    pub max_payload: usize,
+    /// Longest protocol line a client may send. Advertised in `INFO`.
+    pub max_control_line: usize,
+    /// Maximum number of message bytes queued for a client before it is
+    /// closed as a slow consumer.
+    pub max_pending: usize,
+    /// Maximum number of operations queued for a client before it is closed
+    /// as a slow consumer.
+    pub max_pending_msgs: usize,
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions {
+        ServerOptions {
+            host: DEFAULT_HOST.to_string(),
+            port: DEFAULT_PORT,
+            server_name: String::new(),
+            http_port: None,
+            config_file: None,
+            pid_file: None,
+            ports_file_dir: None,
+            debug: false,
+            trace: false,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            authorization: None,
+            auth_timeout: DEFAULT_AUTH_TIMEOUT,
+            write_deadline: DEFAULT_WRITE_DEADLINE,
+            lame_duck_duration: DEFAULT_LAME_DUCK_DURATION,
+            lame_duck_grace_period: DEFAULT_LAME_DUCK_GRACE_PERIOD,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            max_pings_out: DEFAULT_MAX_PINGS_OUT,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            max_control_line: DEFAULT_MAX_CONTROL_LINE,
+            max_pending: DEFAULT_MAX_PENDING,
+            max_pending_msgs: DEFAULT_MAX_PENDING_MSGS,
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Load the options set in the configuration file at `path`, keeping
+    /// the defaults for the others.
+    pub fn from_file(path: &Path) -> Result<ServerOptions, Error> {
+        let config = conf::parse_file(path)?;
+        let mut opts = ServerOptions {
+            config_file: Some(path.to_path_buf()),
+            ..ServerOptions::default()
+        };
+        opts.apply_config(&config)?;
+        Ok(opts)
+    }
+
+    /// Set the options found in `config`. Keys are the ones of `nats-server`
+    /// and are case insensitive. Unknown keys are an error, unless they only
+    /// define a variable used elsewhere.
+    pub fn apply_config(&mut self, config: &Map) -> Result<(), Error> {
+        for (key, item) in config.iter() {
+            match key.to_ascii_lowercase().as_str() {
+                "host" | "net" => self.host = item.as_str()?.to_string(),
+                "port" => self.port = item.as_port()?,
+                "listen" => self.set_listen(item)?,
+                "server_name" => self.server_name = item.as_str()?.to_string(),
+                "http_port" | "monitor_port" => self.http_port = Some(item.as_port()?),
+                "pid_file" => self.pid_file = Some(item.as_str()?.into()),
+                "ports_file_dir" => self.ports_file_dir = Some(item.as_str()?.into()),
+                "debug" => self.debug = item.as_bool()?,
+                "trace" => self.trace = item.as_bool()?,
+                "max_connections" | "max_conn" => self.max_connections = item.as_usize()?,
+                "authorization" => self.set_authorization(item)?,
+                "write_deadline" => self.write_deadline = item.as_duration()?,
+                "lame_duck_duration" => self.lame_duck_duration = item.as_duration()?,
+                "lame_duck_grace_period" => self.lame_duck_grace_period = item.as_duration()?,
+                "ping_interval" => self.ping_interval = item.as_duration()?,
+                "ping_max" => self.max_pings_out = item.as_usize()?,
+                "max_payload" => self.max_payload = item.as_size()?,
+                "max_control_line" => self.max_control_line = item.as_size()?,
+                "max_pending" => self.max_pending = item.as_size()?,
+                _ if item.is_used() => {}
+                _ => return Err(item.error(format!("unknown field \"{}\"", key))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of the options that differ between `self` and `other`.
+    pub fn changes(&self, other: &ServerOptions) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+        macro_rules! compare {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field != other.$field {
+                        changes.push(stringify!($field));
+                    }
+                )*
+            };
+        }
+        compare!(
+            host,
+            port,
+            server_name,
+            http_port,
+            config_file,
+            pid_file,
+            ports_file_dir,
+            debug,
+            trace,
+            max_connections,
+            authorization,
+            auth_timeout,
+            write_deadline,
+            lame_duck_duration,
+            lame_duck_grace_period,
+            ping_interval,
+            max_pings_out,
+            max_payload,
+            max_control_line,
+            max_pending,
+            max_pending_msgs
+        );
+        changes
+    }
+
+    /// Returns `false` for the options a running server cannot change.
+    pub fn is_reloadable(name: &str) -> bool {
+        !NOT_RELOADABLE.contains(&name)
+    }
+
+    /// The most detailed level to log at, given `debug` and `trace`.
+    pub fn log_level(&self) -> LevelFilter {
+        if self.trace {
+            LevelFilter::Trace
+        } else if self.debug {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Info
+        }
+    }
+
+    /// The `authorization` block holds a `token`, a `user` and `password`, or
+    /// a list of `users`, and the auth `timeout`.
+    fn set_authorization(&mut self, item: &Item) -> Result<(), Error> {
+        let mut token = None;
+        let mut users = Vec::new();
+        let (mut username, mut password) = (None, None);
+        for (key, value) in item.as_map()?.iter() {
+            match key.to_ascii_lowercase().as_str() {
+                "token" => token = Some(value.as_str()?.to_string()),
+                "user" | "username" => username = Some(value.as_str()?.to_string()),
+                "password" | "pass" => password = Some(value.as_str()?.to_string()),
+                "users" => {
+                    for user in value.as_array()? {
+                        users.push(user_config(user)?);
+                    }
+                }
+                "timeout" => self.auth_timeout = value.as_duration()?,
+                _ if value.is_used() => {}
+                _ => return Err(value.error(format!("unknown field \"{}\"", key))),
+            }
+        }
+        match (username, password) {
+            (Some(username), Some(password)) => users.push(User { username, password }),
+            (None, None) => {}
+            _ => return Err(item.error("user and password must be set together")),
+        }
+        self.authorization = match token {
+            Some(_) if !users.is_empty() => return Err(item.error("cannot have a token and users")),
+            Some(token) => Some(Authorization::Token(token)),
+            None if users.is_empty() => None,
+            None => Some(Authorization::Users(users)),
+        };
+        Ok(())
+    }
+
+    /// `listen` is a port, or `host:port` where either part can be left out.
+    fn set_listen(&mut self, item: &Item) -> Result<(), Error> {
+        if let Ok(port) = item.as_port() {
+            self.port = port;
+            return Ok(());
+        }
+        let invalid = || item.error("expected a port or host:port");
+        let (host, port) = item.as_str()?.rsplit_once(':').ok_or_else(invalid)?;
+        if !port.is_empty() {
+            self.port = port.parse().map_err(|_| invalid())?;
+        }
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if !host.is_empty() {
+            self.host = host.to_string();
+        }
+        Ok(())
+    }
+}
+
+/// An entry of `users`, with a `user` and a `password`.
+fn user_config(item: &Item) -> Result<User, Error> {
+    let (mut username, mut password) = (None, None);
+    for (key, value) in item.as_map()?.iter() {
+        match key.to_ascii_lowercase().as_str() {
+            "user" | "username" => username = Some(value.as_str()?.to_string()),
+            "password" | "pass" => password = Some(value.as_str()?.to_string()),
+            _ => return Err(value.error(format!("unknown field \"{}\"", key))),
+        }
+    }
+    match (username, password) {
+        (Some(username), Some(password)) => Ok(User { username, password }),
+        _ => Err(item.error("users need a user and a password")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_config() {
+        let config = conf::parse(
+            "
+            listen: 127.0.0.1:4333
+            server_name: n1
+            MAX_PAYLOAD: 2MB
+            max_pending: 128KB
+            ping_interval: \"30s\"
+            write_deadline: 2
+            ping_max: 3
+            debug: true
+            ",
+        )
+        .unwrap();
+        let mut opts = ServerOptions::default();
+        opts.apply_config(&config).unwrap();
+        assert_eq!(opts.host, "127.0.0.1");
+        assert_eq!(opts.port, 4333);
+        assert_eq!(opts.server_name, "n1");
+        assert_eq!(opts.max_payload, 2 << 20);
+        assert_eq!(opts.max_pending, 128 << 10);
+        assert_eq!(opts.ping_interval, Duration::from_secs(30));
+        assert_eq!(opts.write_deadline, Duration::from_secs(2));
+        assert_eq!(opts.max_pings_out, 3);
+        assert!(opts.debug);
+
+        let config = conf::parse("listen: \"[::1]:\"").unwrap();
+        opts.apply_config(&config).unwrap();
+        assert_eq!(opts.host, "::1");
+        assert_eq!(opts.port, 4333);
+
+        // Keys only defining variables are allowed, other unknown keys are not.
+        let config = conf::parse("limit: 1MB\nmax_payload: $limit\n").unwrap();
+        opts.apply_config(&config).unwrap();
+        assert_eq!(opts.max_payload, 1 << 20);
+        for (src, reason) in [
+            ("port: 4222\ncluster: {}", "2:10: unknown field \"cluster\""),
+            ("port: \"4222\"", "1:7: expected a port number"),
+            ("max_payload: 1XB", "1:14: invalid size"),
+        ] {
+            match opts.apply_config(&conf::parse(src).unwrap()) {
+                Err(Error::ConfigError(err)) => assert_eq!(err, reason),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let opts = ServerOptions::default();
+        assert!(opts.changes(&opts.clone()).is_empty());
+
+        let other = ServerOptions {
+            port: 4333,
+            max_payload: 1024,
+            trace: true,
+            ..ServerOptions::default()
+        };
+        let changes = opts.changes(&other);
+        assert_eq!(changes, ["port", "trace", "max_payload"]);
+        assert!(!ServerOptions::is_reloadable("port"));
+        assert!(ServerOptions::is_reloadable("max_payload"));
+        assert_eq!(other.log_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_authorization() {
+        let mut opts = ServerOptions::default();
+        let config = conf::parse("authorization { token: s3cr3t, timeout: 0.5 }").unwrap();
+        opts.apply_config(&config).unwrap();
+        assert_eq!(
+            opts.authorization,
+            Some(Authorization::Token("s3cr3t".into()))
+        );
+        assert_eq!(opts.auth_timeout, Duration::from_millis(500));
+
+        let config = conf::parse(
+            "
+            authorization {
+                user: alice
+                password: \"$2a$11$W2zko751KUvVy59mUTWmpOdWjpEm5qhcCZRd05GjI/sSOT.xtiHyG\"
+                users: [{user: bob, password: pass}]
+            }
+            ",
+        )
+        .unwrap();
+        opts.apply_config(&config).unwrap();
+        let users = match &opts.authorization {
+            Some(Authorization::Users(users)) => users,
+            other => panic!("unexpected {:?}", other),
+        };
+        let names: Vec<&str> = users.iter().map(|u| u.username.as_str()).collect();
+        assert_eq!(names, ["bob", "alice"]);
+        assert!(users[1].password.starts_with("$2a$11$"));
+
+        for (src, reason) in [
+            (
+                "authorization { user: alice }",
+                "1:15: user and password must be set together",
+            ),
+            (
+                "authorization { token: t, user: a, pass: p }",
+                "1:15: cannot have a token and users",
+            ),
+            (
+                "authorization { users: [{user: a}] }",
+                "1:25: users need a user and a password",
+            ),
+            (
+                "authorization { tokens: t }",
+                "1:25: unknown field \"tokens\"",
+            ),
+        ] {
+            match opts.apply_config(&conf::parse(src).unwrap()) {
+                Err(Error::ConfigError(err)) => assert_eq!(err, reason),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+}
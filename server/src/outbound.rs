@@ -0,0 +1,414 @@
+use bytes::BytesMut;
+use log::debug;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::tcp::OwnedWriteHalf,
+    sync::{mpsc, oneshot, Notify},
+    task::JoinHandle,
+    time,
+};
+use tokio_util::codec::Encoder;
+
+use crate::{
+    errors::Error,
+    protocol::{NatsMessageCodec, ServerOp},
+};
+
+/// Pending frames are written once this many bytes are buffered, even if
+/// more are queued.
+const MAX_BATCH: usize = 64 * 1024;
+
+/// Handle used to queue operations for a connection's writer task.
+#[derive(Debug, Clone)]
+pub(crate) struct Outbound {
+    tx: mpsc::Sender<ServerOp>,
+    pending: Arc<Pending>,
+}
+
+/// Accounting of the messages waiting in the queue, shared by the handles
+/// and the writer.
+#[derive(Debug)]
+struct Pending {
+    /// Size of the messages queued and not written yet.
+    bytes: AtomicUsize,
+    max_bytes: usize,
+    /// Set once a limit was exceeded. The client is not keeping up and
+    /// nothing more is queued for it.
+    slow_consumer: AtomicBool,
+    notify: Notify,
+}
+
+/// The writer task of a connection. It owns the write half of the socket.
+#[derive(Debug)]
+pub(crate) struct Writer {
+    handle: JoinHandle<Result<(), Error>>,
+    close: oneshot::Sender<Close>,
+}
+
+/// How the writer is asked to stop.
+#[derive(Debug)]
+enum Close {
+    /// Write out everything queued first.
+    Drain,
+    /// Drop everything queued and only write this last operation.
+    Abort(ServerOp),
+}
+
+impl Outbound {
+    /// Creates an outbound queue holding up to `max_msgs` operations and
+    /// `max_bytes` of messages, and the receiving end drained by a writer.
+    pub(crate) fn channel(
+        max_msgs: usize,
+        max_bytes: usize,
+    ) -> (Outbound, mpsc::Receiver<ServerOp>) {
+        let (tx, rx) = mpsc::channel(max_msgs);
+        let pending = Arc::new(Pending {
+            bytes: AtomicUsize::new(0),
+            max_bytes,
+            slow_consumer: AtomicBool::new(false),
+            notify: Notify::new(),
+        });
+        (Outbound { tx, pending }, rx)
+    }
+
+    /// Queue `op`, waiting for room if the queue is full. Fails with
+    /// `SlowConsumer` if the connection is marked as one while waiting.
+    pub(crate) async fn send(&self, op: ServerOp) -> Result<(), Error> {
+        let size = pending_size(&op);
+        self.pending.bytes.fetch_add(size, Ordering::AcqRel);
+        let res = tokio::select! {
+            biased;
+            res = self.tx.send(op) => res.map_err(|_| Error::ConnectionClosed),
+            _ = self.slow_consumer() => Err(Error::SlowConsumer),
+        };
+        res.map_err(|err| {
+            self.pending.bytes.fetch_sub(size, Ordering::AcqRel);
+            // A writer past its write deadline stops after marking the
+            // connection as a slow consumer.
+            if self.is_slow_consumer() {
+                Error::SlowConsumer
+            } else {
+                err
+            }
+        })
+    }
+
+    /// Queue `op` without waiting. Returns `false` if the connection is gone
+    /// or is a slow consumer. Going over the pending limits marks the
+    /// connection as a slow consumer.
+    pub(crate) fn try_send(&self, op: ServerOp) -> bool {
+        if self.pending.slow_consumer.load(Ordering::Acquire) {
+            return false;
+        }
+        let size = pending_size(&op);
+        let pending = self.pending.bytes.fetch_add(size, Ordering::AcqRel) + size;
+        if pending > self.pending.max_bytes {
+            self.pending.bytes.fetch_sub(size, Ordering::AcqRel);
+            self.pending.mark_slow_consumer();
+            return false;
+        }
+        match self.tx.try_send(op) {
+            Ok(()) => true,
+            Err(err) => {
+                self.pending.bytes.fetch_sub(size, Ordering::AcqRel);
+                if let mpsc::error::TrySendError::Full(_) = err {
+                    self.pending.mark_slow_consumer();
+                }
+                false
+            }
+        }
+    }
+
+    /// Completes once the writer task has stopped.
+    pub(crate) async fn closed(&self) {
+        self.tx.closed().await
+    }
+
+    /// Completes once the connection exceeded its pending limits or a
+    /// write went past the write deadline.
+    pub(crate) async fn slow_consumer(&self) {
+        // Check the flag after registering, so a notification sent in
+        // between is not missed.
+        let notified = self.pending.notify.notified();
+        if !self.is_slow_consumer() {
+            notified.await;
+        }
+    }
+
+    /// Returns `true` once the connection was marked as a slow consumer.
+    pub(crate) fn is_slow_consumer(&self) -> bool {
+        self.pending.slow_consumer.load(Ordering::Acquire)
+    }
+}
+
+impl Pending {
+    fn mark_slow_consumer(&self) {
+        if !self.slow_consumer.swap(true, Ordering::AcqRel) {
+            self.notify.notify_waiters();
+        }
+    }
+}
+
+impl Writer {
+    /// Spawn the writer task for `socket`, draining the queue of `outbound`.
+    /// A write taking longer than `deadline` marks the connection as a slow
+    /// consumer and stops the writer.
+    pub(crate) fn spawn(
+        socket: OwnedWriteHalf,
+        outbound: &Outbound,
+        rx: mpsc::Receiver<ServerOp>,
+        deadline: Duration,
+    ) -> Writer {
+        let (close, closing) = oneshot::channel();
+        let pending = outbound.pending.clone();
+        let handle = tokio::spawn(write_loop(socket, rx, pending, closing, deadline));
+        Writer { handle, close }
+    }
+
+    /// Write out everything queued so far and close the socket.
+    pub(crate) async fn close(self) -> Result<(), Error> {
+        let _ = self.close.send(Close::Drain);
+        Writer::join(self.handle).await
+    }
+
+    /// Drop everything queued, write `op` and close the socket. If the writer
+    /// is stuck writing to a client that does not read, the socket is closed
+    /// without writing `op`.
+    pub(crate) async fn abort(self, op: ServerOp) -> Result<(), Error> {
+        let _ = self.close.send(Close::Abort(op));
+        Writer::join(self.handle).await
+    }
+
+    async fn join(handle: JoinHandle<Result<(), Error>>) -> Result<(), Error> {
+        match handle.await {
+            Ok(res) => res,
+            // The task panicked or was cancelled, nothing left to flush.
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// Approximate number of bytes a message takes on the wire. Only messages
+/// count against the pending limit, the connection's own replies do not.
+fn pending_size(op: &ServerOp) -> usize {
+    match op {
+        ServerOp::Msg(msg) => {
+            let headers = msg.headers.as_ref().map_or(0, |headers| headers.len());
+            msg.subject.len()
+                + msg.sid.len()
+                + msg.reply.as_ref().map_or(0, |reply| reply.len())
+                + headers
+                + msg.payload.len()
+        }
+        _ => 0,
+    }
+}
+
+/// Wait for queued operations and write them in batches. Everything queued
+/// while a batch is being encoded goes out in the same write, and the
+/// socket is only flushed once the queue is drained.
+async fn write_loop(
+    mut socket: OwnedWriteHalf,
+    mut rx: mpsc::Receiver<ServerOp>,
+    pending: Arc<Pending>,
+    mut closing: oneshot::Receiver<Close>,
+    deadline: Duration,
+) -> Result<(), Error> {
+    let mut codec = NatsMessageCodec::new();
+    let mut buf = BytesMut::with_capacity(MAX_BATCH);
+    let mut closed = false;
+    loop {
+        let op = tokio::select! {
+            // Look at the close signal first, an abort skips the queue.
+            biased;
+            close = &mut closing, if !closed => {
+                // Stop taking new operations.
+                closed = true;
+                rx.close();
+                match close {
+                    Ok(Close::Abort(op)) => {
+                        while rx.try_recv().is_ok() {}
+                        op
+                    }
+                    // Write what is queued.
+                    _ => continue,
+                }
+            }
+            op = rx.recv() => match op {
+                Some(op) => op,
+                None => break,
+            },
+        };
+        pending.bytes.fetch_sub(pending_size(&op), Ordering::AcqRel);
+        codec.encode(op, &mut buf)?;
+        while buf.len() < MAX_BATCH {
+            match rx.try_recv() {
+                Ok(op) => {
+                    pending.bytes.fetch_sub(pending_size(&op), Ordering::AcqRel);
+                    codec.encode(op, &mut buf)?;
+                }
+                Err(_) => break,
+            }
+        }
+
+        let write = socket.write_all(&buf);
+        let expired = time::sleep(deadline);
+        tokio::pin!(write, expired);
+        loop {
+            tokio::select! {
+                biased;
+                res = &mut write => {
+                    res?;
+                    break;
+                }
+                _ = &mut expired => {
+                    debug!("write deadline exceeded");
+                    pending.mark_slow_consumer();
+                    return Ok(());
+                }
+                close = &mut closing, if !closed => {
+                    closed = true;
+                    rx.close();
+                    if let Ok(Close::Abort(_)) = close {
+                        // The client is not reading, give up on it.
+                        debug!("writer aborted");
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        buf.clear();
+        if rx.is_empty() {
+            socket.flush().await?;
+        }
+    }
+    debug!("writer done");
+    socket.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{protocol::Msg, subject::Subject};
+    use bytes::Bytes;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, (server, _)) = tokio::try_join!(client, listener.accept()).unwrap();
+        (client, server)
+    }
+
+    const DEADLINE: Duration = Duration::from_secs(10);
+
+    fn msg(payload: &'static [u8]) -> ServerOp {
+        ServerOp::Msg(Msg {
+            subject: Subject::from_static("foo"),
+            sid: Bytes::from_static(b"1"),
+            reply: None,
+            headers: None,
+            payload: Bytes::from_static(payload),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_close_writes_queued_ops() {
+        let (mut client, server) = socket_pair().await;
+        let (_read_half, write_half) = server.into_split();
+
+        let (outbound, rx) = Outbound::channel(16, 1024);
+        for op in [ServerOp::Ok, ServerOp::Ping, ServerOp::Pong] {
+            assert!(outbound.try_send(op));
+        }
+        let writer = Writer::spawn(write_half, &outbound, rx, DEADLINE);
+        outbound
+            .send(ServerOp::Err("Stale Connection".into()))
+            .await
+            .unwrap();
+        writer.close().await.unwrap();
+
+        // Queuing after close fails instead of being lost silently.
+        assert!(!outbound.try_send(ServerOp::Ok));
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(
+            &received[..],
+            &b"+OK\r\nPING\r\nPONG\r\n-ERR 'Stale Connection'\r\n"[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer() {
+        let (mut client, server) = socket_pair().await;
+        let (_read_half, write_half) = server.into_split();
+
+        // Room for two of these messages.
+        let (outbound, rx) = Outbound::channel(16, 2 * 6);
+        assert!(outbound.try_send(msg(b"hi")));
+        assert!(outbound.try_send(msg(b"hi")));
+        assert!(!outbound.try_send(msg(b"hi")));
+        outbound.slow_consumer().await;
+        // Nothing is queued anymore, not even operations that would fit.
+        assert!(!outbound.try_send(ServerOp::Ok));
+
+        let writer = Writer::spawn(write_half, &outbound, rx, DEADLINE);
+        writer
+            .abort(ServerOp::Err("Slow Consumer".into()))
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(&received[..], &b"-ERR 'Slow Consumer'\r\n"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_send_fails_on_slow_consumer() {
+        let (outbound, _rx) = Outbound::channel(1, 1024);
+        outbound.send(ServerOp::Ok).await.unwrap();
+
+        // The queue is full, the reply waits for room.
+        let waiting = tokio::spawn({
+            let outbound = outbound.clone();
+            async move { outbound.send(ServerOp::Pong).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!outbound.try_send(msg(b"hi")));
+        assert!(matches!(waiting.await.unwrap(), Err(Error::SlowConsumer)));
+    }
+
+    #[tokio::test]
+    async fn test_write_deadline() {
+        let (_client, server) = socket_pair().await;
+        let (_read_half, write_half) = server.into_split();
+
+        // The client never reads, so a large enough write cannot complete.
+        let payload = Bytes::from(vec![b'x'; 16 * 1024 * 1024]);
+        let (outbound, rx) = Outbound::channel(16, 2 * payload.len());
+        let writer = Writer::spawn(write_half, &outbound, rx, Duration::from_millis(50));
+        assert!(outbound.try_send(ServerOp::Msg(Msg {
+            subject: Subject::from_static("foo"),
+            sid: Bytes::from_static(b"1"),
+            reply: None,
+            headers: None,
+            payload,
+        })));
+
+        outbound.slow_consumer().await;
+        outbound.closed().await;
+        writer.close().await.unwrap();
+    }
+}
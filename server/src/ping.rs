@@ -0,0 +1,24 @@
+use crate::{connection::Connection, errors::Error, protocol::ServerOp};
+
+/// `PING` keep-alive, sent by either side.
+#[derive(Debug)]
+pub struct Ping;
+
+/// `PONG`, the reply to a `PING`.
+#[derive(Debug)]
+pub struct Pong;
+
+impl Ping {
+    /// Answer a client `PING` with a `PONG`.
+    pub(crate) async fn apply(self, dst: &mut Connection) -> Result<(), Error> {
+        dst.send(ServerOp::Pong).await
+    }
+}
+
+impl Pong {
+    /// The client answered our `PING`, so it is still alive.
+    pub(crate) async fn apply(self, dst: &mut Connection) -> Result<(), Error> {
+        dst.pings_out = 0;
+        Ok(())
+    }
+}
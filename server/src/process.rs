@@ -0,0 +1,208 @@
+use std::{
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::{errors::Error, options::ServerOptions};
+
+/// A command `rnats-server --signal` sends to a running server, as the
+/// signal the server handles it on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// Kill the server right away, with SIGKILL.
+    Stop,
+    /// Shut down gracefully, with SIGINT.
+    Quit,
+    /// Shut down gracefully, with SIGTERM.
+    Term,
+    /// Reload the configuration file, with SIGHUP.
+    Reload,
+    /// Enter lame duck mode, with SIGUSR2.
+    LameDuck,
+}
+
+impl Signal {
+    /// Send the signal to the process `pid`.
+    #[cfg(unix)]
+    pub fn send(self, pid: u32) -> Result<(), Error> {
+        let signal = match self {
+            Signal::Stop => libc::SIGKILL,
+            Signal::Quit => libc::SIGINT,
+            Signal::Term => libc::SIGTERM,
+            Signal::Reload => libc::SIGHUP,
+            Signal::LameDuck => libc::SIGUSR2,
+        };
+        // SAFETY: `kill` has no memory safety requirements.
+        if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+            let err = std::io::Error::last_os_error();
+            return Err(Error::SignalError(format!(
+                "sending {} to {}: {}",
+                self, pid, err
+            )));
+        }
+        Ok(())
+    }
+
+    /// Send the signal to the process `pid`.
+    #[cfg(not(unix))]
+    pub fn send(self, _pid: u32) -> Result<(), Error> {
+        Err(Error::SignalError(format!(
+            "sending {} is not supported on this platform",
+            self
+        )))
+    }
+}
+
+impl FromStr for Signal {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Signal, Error> {
+        match s {
+            "stop" => Ok(Signal::Stop),
+            "quit" => Ok(Signal::Quit),
+            "term" => Ok(Signal::Term),
+            "reload" => Ok(Signal::Reload),
+            "ldm" => Ok(Signal::LameDuck),
+            _ => Err(Error::InvalidFlag(format!("unknown signal {:?}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Signal::Stop => "stop",
+            Signal::Quit => "quit",
+            Signal::Term => "term",
+            Signal::Reload => "reload",
+            Signal::LameDuck => "ldm",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Read the pid a server wrote to `path`.
+pub fn read_pid_file(path: &Path) -> Result<u32, Error> {
+    let pid = fs::read_to_string(path).map_err(|err| {
+        Error::SignalError(format!(
+            "could not read pid file {}: {}",
+            path.display(),
+            err
+        ))
+    })?;
+    pid.trim()
+        .parse()
+        .map_err(|_| Error::SignalError(format!("invalid pid in {}", path.display())))
+}
+
+/// Contents of the ports file, the URLs clients connect to.
+#[derive(Debug, Serialize)]
+struct Ports {
+    nats: Vec<String>,
+}
+
+/// The URL clients on this host connect to for a server listening on
+/// `addr`. A server listening on every interface is reached on loopback.
+fn client_url(mut addr: SocketAddr) -> String {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        _ => {}
+    }
+    format!("nats://{}", addr)
+}
+
+/// The pid and ports files of a running server. Dropping it removes them.
+#[derive(Debug, Default)]
+pub(crate) struct ProcessFiles {
+    paths: Vec<PathBuf>,
+}
+
+impl ProcessFiles {
+    /// Write the pid file and the ports file asked for in `opts`, for a
+    /// server listening on `addr`.
+    pub(crate) fn write(opts: &ServerOptions, addr: SocketAddr) -> Result<ProcessFiles, Error> {
+        let mut files = ProcessFiles::default();
+        let pid = std::process::id();
+        if let Some(path) = &opts.pid_file {
+            fs::write(path, pid.to_string())?;
+            files.paths.push(path.clone());
+        }
+        if let Some(dir) = &opts.ports_file_dir {
+            // Named after the executable, as `nats-server` does.
+            let exe = std::env::current_exe()
+                .ok()
+                .and_then(|exe| exe.file_stem().map(|s| s.to_string_lossy().into_owned()))
+                .unwrap_or_else(|| "rnats-server".to_string());
+            let path = dir.join(format!("{}_{}.ports", exe, pid));
+            let ports = Ports {
+                nats: vec![client_url(addr)],
+            };
+            fs::write(&path, serde_json::to_vec(&ports)?)?;
+            files.paths.push(path);
+        }
+        Ok(files)
+    }
+}
+
+impl Drop for ProcessFiles {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            if let Err(err) = fs::remove_file(path) {
+                warn!("could not remove {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_files() {
+        let dir = std::env::temp_dir().join(format!("rnats-process-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let opts = ServerOptions {
+            pid_file: Some(dir.join("rnats.pid")),
+            ports_file_dir: Some(dir.clone()),
+            ..ServerOptions::default()
+        };
+        let files = ProcessFiles::write(&opts, "127.0.0.1:4333".parse().unwrap()).unwrap();
+        let pid = read_pid_file(&dir.join("rnats.pid")).unwrap();
+        assert_eq!(pid, std::process::id());
+        let ports = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "ports"))
+            .unwrap();
+        assert!(ports
+            .to_string_lossy()
+            .ends_with(&format!("_{}.ports", pid)));
+        assert_eq!(
+            fs::read_to_string(&ports).unwrap(),
+            r#"{"nats":["nats://127.0.0.1:4333"]}"#
+        );
+
+        drop(files);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_client_url() {
+        for (addr, url) in [
+            ("127.0.0.1:4222", "nats://127.0.0.1:4222"),
+            ("0.0.0.0:4222", "nats://127.0.0.1:4222"),
+            ("[::]:4222", "nats://[::1]:4222"),
+            ("[fe80::1]:4222", "nats://[fe80::1]:4222"),
+        ] {
+            assert_eq!(client_url(addr.parse().unwrap()), url);
+        }
+    }
+}
@@ -1,161 +1,897 @@
 use crate::{
-    connection::Connection, errors::Error, publish::Publish, server::Db, subscribe::Subscribe,
+    connect::Connect,
+    connection::Connection,
+    errors::Error,
+    headers::Headers,
+    info::ServerInfo,
+    options::{DEFAULT_MAX_CONTROL_LINE, DEFAULT_MAX_PAYLOAD},
+    ping::{Ping, Pong},
+    publish::Publish,
+    server::Db,
+    subject::Subject,
+    subscribe::Subscribe,
+    unsubscribe::Unsubscribe,
 };
 use bytes::{Buf, Bytes, BytesMut};
 
-use log::info;
 use subslice::SubsliceExt;
 use tokio_util::codec::{Decoder, Encoder};
 
 #[derive(Debug)]
 pub struct NatsMessageCodec {
     state: ParseState,
+    /// Number of bytes decoded so far, used to report where a protocol
+    /// error occurred.
+    pos: usize,
+    /// Largest payload accepted in `PUB` and `HPUB`.
+    max_payload: usize,
+    /// Longest control line accepted. Checked before the whole line has
+    /// arrived, so a client cannot make the buffer grow without bounds.
+    max_control_line: usize,
 }
 
 impl NatsMessageCodec {
     pub fn new() -> NatsMessageCodec {
+        NatsMessageCodec::with_limits(DEFAULT_MAX_PAYLOAD, DEFAULT_MAX_CONTROL_LINE)
+    }
+
+    /// Creates a codec rejecting payloads larger than `max_payload` and
+    /// control lines longer than `max_control_line`.
+    pub fn with_limits(max_payload: usize, max_control_line: usize) -> NatsMessageCodec {
         NatsMessageCodec {
             state: ParseState::OpStart,
+            pos: 0,
+            max_payload,
+            max_control_line,
+        }
+    }
+
+    /// Change the limits, e.g. after the server reloaded its options.
+    /// Returns `true` if they were different.
+    pub fn set_limits(&mut self, max_payload: usize, max_control_line: usize) -> bool {
+        let changed = (self.max_payload, self.max_control_line) != (max_payload, max_control_line);
+        self.max_payload = max_payload;
+        self.max_control_line = max_control_line;
+        changed
+    }
+
+    /// Returns the position of the `\n` ending the control line at the start
+    /// of `src`, or `None` if it has not fully arrived yet.
+    fn line_end(&self, src: &[u8]) -> Result<Option<usize>, Error> {
+        match src.find(b"\n") {
+            Some(end) if end > self.max_control_line => Err(Error::MaxControlLineExceeded),
+            Some(end) => Ok(Some(end)),
+            None if src.len() > self.max_control_line => Err(Error::MaxControlLineExceeded),
+            None => Ok(None),
+        }
+    }
+
+    /// Fails if a client announces a payload larger than `max_payload`,
+    /// before it gets buffered.
+    fn check_payload(&self, size: usize) -> Result<(), Error> {
+        if size > self.max_payload {
+            return Err(Error::MaxPayloadViolation);
+        }
+        Ok(())
+    }
+
+    /// Consume `n` bytes of `src`.
+    fn advance(&mut self, src: &mut BytesMut, n: usize) {
+        src.advance(n);
+        self.pos += n;
+    }
+
+    /// A protocol error in `op`, `offset` bytes into what is left to decode.
+    fn error(&self, op: &[u8], offset: usize) -> Error {
+        Error::ProtocolError {
+            op: String::from_utf8_lossy(op).into_owned(),
+            pos: self.pos + offset,
         }
     }
+
+    /// The error for arguments of `op` that cannot be parsed.
+    fn invalid(&self, op: Op) -> Error {
+        self.error(op.name().as_bytes(), 0)
+    }
+
+    fn subject(&self, op: Op, arg: &Bytes) -> Result<Subject, Error> {
+        Subject::from_bytes(arg.clone()).map_err(|_| self.invalid(op))
+    }
+
+    fn text<'a>(&self, op: Op, arg: &'a [u8]) -> Result<&'a str, Error> {
+        std::str::from_utf8(arg).map_err(|_| self.invalid(op))
+    }
+
+    fn number(&self, op: Op, arg: &[u8]) -> Result<usize, Error> {
+        self.text(op, arg)?.parse().map_err(|_| self.invalid(op))
+    }
+
+    /// Parse the arguments of `op`, found on the control `line`. Returns
+    /// `None` for `PUB` and `HPUB`, whose payload is read next. Subjects
+    /// are slices of `line`, they are not copied.
+    fn parse_args(&mut self, op: Op, line: Bytes) -> Result<Option<NatsProtocol>, Error> {
+        if op == Op::Connect {
+            // CONNECT {["option_name":option_value],...}\r\n
+            let connect = serde_json::from_slice(&line)?;
+            return Ok(Some(NatsProtocol::Connect(connect)));
+        }
+
+        let args = split_args(&line);
+        let protocol = match (op, &args[..]) {
+            // PING\r\n
+            (Op::Ping, []) => NatsProtocol::Ping(Ping),
+            // PONG\r\n
+            (Op::Pong, []) => NatsProtocol::Pong(Pong),
+            // SUB <subject> [queue group] <sid>\r\n
+            (Op::Sub, [subject, sid]) => NatsProtocol::Sub(Subscribe::new(
+                self.subject(op, subject)?,
+                None,
+                self.text(op, sid)?,
+            )),
+            (Op::Sub, [subject, queue, sid]) => NatsProtocol::Sub(Subscribe::new(
+                self.subject(op, subject)?,
+                Some(self.text(op, queue)?),
+                self.text(op, sid)?,
+            )),
+            // UNSUB <sid> [max_msgs]\r\n
+            (Op::Unsub, [sid]) => NatsProtocol::Unsub(Unsubscribe::new(self.text(op, sid)?, None)),
+            (Op::Unsub, [sid, max_msgs]) => NatsProtocol::Unsub(Unsubscribe::new(
+                self.text(op, sid)?,
+                Some(self.number(op, max_msgs)?),
+            )),
+            // PUB <subject> [reply-to] <#bytes>\r\n
+            (Op::Pub, [subject, size]) => {
+                return self.start_payload(op, subject, None, None, size);
+            }
+            (Op::Pub, [subject, reply, size]) => {
+                return self.start_payload(op, subject, Some(reply), None, size);
+            }
+            // HPUB <subject> [reply-to] <#header bytes> <#total bytes>\r\n
+            (Op::Hpub, [subject, hdr_size, size]) => {
+                return self.start_payload(op, subject, None, Some(hdr_size), size);
+            }
+            (Op::Hpub, [subject, reply, hdr_size, size]) => {
+                return self.start_payload(op, subject, Some(reply), Some(hdr_size), size);
+            }
+            _ => return Err(self.invalid(op)),
+        };
+        Ok(Some(protocol))
+    }
+
+    /// Check the sizes on the control line of a `PUB` or `HPUB` and start
+    /// reading its payload.
+    fn start_payload(
+        &mut self,
+        op: Op,
+        subject: &Bytes,
+        reply: Option<&Bytes>,
+        hdr_size: Option<&Bytes>,
+        size: &Bytes,
+    ) -> Result<Option<NatsProtocol>, Error> {
+        let size = self.number(op, size)?;
+        let hdr_size = match hdr_size {
+            Some(hdr_size) => Some(self.number(op, hdr_size)?),
+            None => None,
+        };
+        if hdr_size.is_some_and(|hdr_size| hdr_size > size) {
+            return Err(self.invalid(op));
+        }
+        self.check_payload(size)?;
+        self.state = ParseState::OpPayload(PubArgs {
+            subject: self.subject(op, subject)?,
+            reply: reply.map(|reply| self.subject(op, reply)).transpose()?,
+            hdr_size,
+            size,
+        });
+        Ok(None)
+    }
+}
+
+impl Default for NatsMessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
 }
+
+/// The operations a client can send.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Connect,
+    Sub,
+    Unsub,
+    Pub,
+    Hpub,
+    Ping,
+    Pong,
+}
+
+const OPS: [Op; 7] = [
+    Op::Connect,
+    Op::Sub,
+    Op::Unsub,
+    Op::Pub,
+    Op::Hpub,
+    Op::Ping,
+    Op::Pong,
+];
+
+impl Op {
+    fn name(self) -> &'static str {
+        match self {
+            Op::Connect => "CONNECT",
+            Op::Sub => "SUB",
+            Op::Unsub => "UNSUB",
+            Op::Pub => "PUB",
+            Op::Hpub => "HPUB",
+            Op::Ping => "PING",
+            Op::Pong => "PONG",
+        }
+    }
+
+    /// Look up the operation called `name`, ignoring case. Returns
+    /// `Ok(None)` if `name` is incomplete and could still become one once
+    /// more bytes arrive.
+    fn parse(name: &[u8], complete: bool) -> Result<Option<Op>, ()> {
+        let prefix_of = |op: &Op| {
+            let op = op.name().as_bytes();
+            op.len() >= name.len() && op[..name.len()].eq_ignore_ascii_case(name)
+        };
+        if !OPS.iter().any(prefix_of) {
+            return Err(());
+        }
+        if !complete {
+            return Ok(None);
+        }
+        OPS.iter()
+            .find(|op| op.name().as_bytes().eq_ignore_ascii_case(name))
+            .map(|op| Some(*op))
+            .ok_or(())
+    }
+}
+
 #[derive(Debug)]
 pub enum ParseState {
+    /// Reading the name of the next operation.
     OpStart,
-    OpSub,
-    OpPub,
+    /// Reading the arguments of an operation up to the end of its control
+    /// line.
+    OpArgs(Op),
+    /// Reading the payload of a `PUB` or `HPUB`.
+    OpPayload(PubArgs),
+}
+
+/// The control line of a `PUB` or `HPUB` whose payload is being read.
+#[derive(Debug)]
+pub struct PubArgs {
+    subject: Subject,
+    reply: Option<Subject>,
+    /// Size of the headers, only set for `HPUB`.
+    hdr_size: Option<usize>,
+    /// Size of the headers and the payload together.
+    size: usize,
+}
+
+impl PubArgs {
+    fn op(&self) -> Op {
+        match self.hdr_size {
+            Some(_) => Op::Hpub,
+            None => Op::Pub,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum NatsProtocol {
     // Msg(NatsMsg),
+    Connect(Connect),
     Sub(Subscribe),
+    Unsub(Unsubscribe),
     Pub(Publish),
+    Ping(Ping),
+    Pong(Pong),
 }
 
 impl NatsProtocol {
     pub(crate) async fn apply(self, db: &Db, dst: &mut Connection) -> Result<(), Error> {
         use NatsProtocol::*;
+        // Clients that have to authenticate start with `CONNECT`.
+        if !dst.connected && !matches!(self, Connect(_)) && db.options().authorization.is_some() {
+            return Err(Error::AuthorizationViolation);
+        }
         match self {
-            Sub(s) => s.apply(db, dst).await,
+            Connect(c) => c.apply(db, dst).await,
+            Sub(s) => s.apply(dst).await,
+            Unsub(u) => u.apply(dst).await,
             Pub(p) => p.apply(db, dst).await,
+            Ping(p) => p.apply(dst).await,
+            Pong(p) => p.apply(dst).await,
         }
     }
 }
 
+/// Returns `true` for the bytes separating the arguments of a control line.
+fn is_space(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
 impl Decoder for NatsMessageCodec {
     type Item = NatsProtocol;
     type Error = Error;
 
+    /// Decodes byte by byte: the operation name is checked as soon as it
+    /// arrives, so garbage is refused without waiting for a line end.
+    /// Control lines end with `\r\n`, a bare `\n` is tolerated. Payloads
+    /// must be followed by exactly `\r\n`.
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         use ParseState::*;
-        info!("recv msg {:?}", std::str::from_utf8(&src[..]));
         loop {
-            match self.state {
+            // Every branch puts the state back if it needs more bytes.
+            match std::mem::replace(&mut self.state, OpStart) {
                 OpStart => {
-                    if src.starts_with(b"SUB ") {
-                        self.state = OpSub;
-                        src.advance(4);
-                    } else if src.starts_with(b"PUB ") {
-                        self.state = OpPub;
-                        src.advance(4);
-                    } else {
-                        return Ok(None);
-                    }
+                    let end = src
+                        .iter()
+                        .position(|&b| is_space(b) || b == b'\r' || b == b'\n');
+                    let name = &src[..end.unwrap_or(src.len())];
+                    let op = match Op::parse(name, end.is_some()) {
+                        Ok(Some(op)) => op,
+                        Ok(None) => return Ok(None),
+                        Err(()) => return Err(self.error(name, 0)),
+                    };
+                    self.advance(src, name.len());
+                    self.state = OpArgs(op);
                 }
-                OpSub => {
-                    let line_end = match src.find(b"\r\n") {
+                OpArgs(op) => {
+                    let end = match self.line_end(src)? {
                         Some(end) => end,
-                        None => return Ok(None),
+                        None => {
+                            self.state = OpArgs(op);
+                            return Ok(None);
+                        }
                     };
-                    // SUB <subject> [subjects...]\r\n
-
-                    let parts = src[..line_end].split(|c| c == &b' ');
-                    let mut channels = Vec::new();
-                    // first arg is always subject
-                    for i in parts.into_iter() {
-                        channels.push(std::str::from_utf8(i)?.to_string())
+                    let mut line = src.split_to(end + 1);
+                    line.truncate(end);
+                    if line.ends_with(b"\r") {
+                        line.truncate(end - 1);
+                    }
+                    let protocol = self.parse_args(op, line.freeze())?;
+                    self.pos += end + 1;
+                    if protocol.is_some() {
+                        return Ok(protocol);
                     }
-                    self.state = OpStart;
-                    src.advance(line_end + 2);
-                    return Ok(Some(NatsProtocol::Sub(Subscribe { channels })));
                 }
-                OpPub => {
-                    // PUB <subject> <len>\r\n<message>\r\n
-                    let line_end = if let Some(end) = src.find(b"\r\n") {
-                        end
-                    } else {
-                        return Ok(None);
-                    };
-                    let mut parts = src[..line_end].split(|c| c == &b' ');
-                    let channel =
-                        std::str::from_utf8(parts.next().ok_or_else(|| Error::ProtocolError)?)?
-                            .to_string();
-                    let size =
-                        std::str::from_utf8(parts.next().ok_or_else(|| Error::ProtocolError)?)?
-                            .parse::<usize>()?;
-                    if line_end + size + 4 <= src.len() {
-                        src.advance(line_end + 2);
-                        let message = src.split_to(size);
-                        src.advance(2);
-                        self.state = OpStart;
-                        return Ok(Some(NatsProtocol::Pub(Publish {
-                            channel,
-                            size,
-                            message: message.freeze(),
-                        })));
-                    } else {
+                OpPayload(args) => {
+                    // [payload]\r\n
+                    let end = args.size + 2;
+                    if src.len() < end {
+                        // Make room for the rest of the payload.
+                        src.reserve(end - src.len());
+                        self.state = OpPayload(args);
                         return Ok(None);
                     }
+                    let op = args.op().name();
+                    if &src[args.size..end] != b"\r\n" {
+                        return Err(self.error(op.as_bytes(), args.size));
+                    }
+                    let start = self.pos;
+                    let mut message = src.split_to(args.size);
+                    self.pos += args.size;
+                    self.advance(src, 2);
+
+                    let headers = match args.hdr_size {
+                        // [headers]\r\n\r\n[payload]
+                        Some(hdr_size) => {
+                            // Only validated, the headers are forwarded as
+                            // they were received.
+                            let headers = message.split_to(hdr_size).freeze();
+                            Headers::parse(&headers).map_err(|_| Error::ProtocolError {
+                                op: op.to_string(),
+                                pos: start,
+                            })?;
+                            Some(headers)
+                        }
+                        None => None,
+                    };
+                    return Ok(Some(NatsProtocol::Pub(Publish::new(
+                        args.subject,
+                        args.reply,
+                        headers,
+                        args.size,
+                        message.freeze(),
+                    ))));
                 }
             }
         }
     }
 }
 
-impl Encoder<(String, Bytes)> for NatsMessageCodec {
+/// Split the arguments of a control line on spaces and tabs. The arguments
+/// share the bytes of `line`.
+fn split_args(line: &Bytes) -> Vec<Bytes> {
+    line.split(|&b| is_space(b))
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| line.slice_ref(arg))
+        .collect()
+}
+
+/// A message delivered to one of the client's subscriptions.
+///
+/// Every field is shared with the published message, so delivering it to
+/// many subscriptions does not copy anything.
+#[derive(Debug)]
+pub struct Msg {
+    pub subject: Subject,
+    pub sid: Bytes,
+    pub reply: Option<Subject>,
+    /// Header section, when present the message is sent as `HMSG`.
+    pub headers: Option<Bytes>,
+    pub payload: Bytes,
+}
+
+/// Everything the server sends to a client.
+#[derive(Debug)]
+pub enum ServerOp {
+    Info(ServerInfo),
+    /// Sent as `HMSG` when the message carries headers, `MSG` otherwise.
+    Msg(Msg),
+    Ping,
+    Pong,
+    Ok,
+    Err(String),
+}
+
+impl Encoder<ServerOp> for NatsMessageCodec {
     type Error = Error;
-    // MESSAGE
-    // MSG <subject> <size>\r\n
-    // <message>\r\n
-    fn encode(&mut self, item: (String, Bytes), dst: &mut BytesMut) -> Result<(), Self::Error> {
-        // unimplemented!()
+
+    fn encode(&mut self, item: ServerOp, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            // INFO {["option_name":option_value],...}\r\n
+            ServerOp::Info(info) => {
+                dst.extend_from_slice(b"INFO ");
+                dst.extend_from_slice(&serde_json::to_vec(&info)?);
+                dst.extend_from_slice(b"\r\n");
+            }
+            ServerOp::Msg(msg) => encode_msg(msg, dst),
+            // PING\r\n
+            ServerOp::Ping => dst.extend_from_slice(b"PING\r\n"),
+            // PONG\r\n
+            ServerOp::Pong => dst.extend_from_slice(b"PONG\r\n"),
+            // +OK\r\n
+            ServerOp::Ok => dst.extend_from_slice(b"+OK\r\n"),
+            // -ERR '<error message>'\r\n
+            ServerOp::Err(reason) => {
+                dst.extend_from_slice(b"-ERR '");
+                dst.extend_from_slice(reason.as_bytes());
+                dst.extend_from_slice(b"'\r\n");
+            }
+        }
+        Ok(())
+    }
+}
+
+// MSG <subject> <sid> [reply-to] <#bytes>\r\n
+// [payload]\r\n
+// HMSG <subject> <sid> [reply-to] <#header bytes> <#total bytes>\r\n
+// [headers]\r\n\r\n[payload]\r\n
+fn encode_msg(msg: Msg, dst: &mut BytesMut) {
+    let reply_len = msg.reply.as_ref().map_or(0, |reply| reply.len() + 1);
+    let hdr_len = msg.headers.as_ref().map_or(0, |headers| headers.len());
+    // Op, sizes and separators fit in the extra 64 bytes.
+    dst.reserve(msg.subject.len() + msg.sid.len() + reply_len + hdr_len + msg.payload.len() + 64);
+
+    if msg.headers.is_some() {
+        dst.extend_from_slice(b"HMSG ");
+    } else {
         dst.extend_from_slice(b"MSG ");
-        dst.extend_from_slice(item.0.as_bytes());
+    }
+    dst.extend_from_slice(msg.subject.as_bytes());
+    dst.extend_from_slice(b" ");
+    dst.extend_from_slice(&msg.sid);
+    if let Some(reply) = &msg.reply {
         dst.extend_from_slice(b" ");
-        dst.extend_from_slice(format!(" {}\r\n", item.1.len()).as_bytes());
-        dst.extend_from_slice(item.1.as_ref());
-        Ok(())
+        dst.extend_from_slice(reply.as_bytes());
+    }
+    if let Some(headers) = &msg.headers {
+        dst.extend_from_slice(b" ");
+        put_decimal(dst, headers.len());
+    }
+    dst.extend_from_slice(b" ");
+    put_decimal(dst, hdr_len + msg.payload.len());
+    dst.extend_from_slice(b"\r\n");
+    if let Some(headers) = &msg.headers {
+        dst.extend_from_slice(headers);
     }
+    dst.extend_from_slice(&msg.payload);
+    dst.extend_from_slice(b"\r\n");
 }
+
+/// Write `n` in decimal, without going through an allocated `String`.
+fn put_decimal(dst: &mut BytesMut, mut n: usize) {
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    dst.extend_from_slice(&digits[start..]);
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::options::ServerOptions;
 
     #[test]
     fn test_decode() {
-        let mut decoder = NatsMessageCodec {
-            state: ParseState::OpStart,
-        };
-        let mut buf = BytesMut::from("aa".as_bytes());
+        let mut decoder = NatsMessageCodec::new();
+        let mut buf = BytesMut::from("PU".as_bytes());
         assert!(decoder.decode(&mut buf).unwrap().is_none());
+        decoder.state = ParseState::OpStart;
         // test pub
         // PUB <subject> <len>\r\n<message>\r\n
         let mut buf = BytesMut::from("PUB subject 5\r\nhello\r\n".as_bytes());
         let result = decoder.decode(&mut buf).unwrap().unwrap();
         println!("{:?}", result);
 
+        // PUB <subject> [reply-to] <#bytes>\r\n[payload]\r\n
+        let mut buf = BytesMut::from("PUB subject reply 5\r\nhello\r\n".as_bytes());
+        match decoder.decode(&mut buf).unwrap().unwrap() {
+            NatsProtocol::Pub(p) => {
+                assert_eq!(p.channel, "subject");
+                assert_eq!(p.reply.as_deref(), Some("reply"));
+                assert_eq!(&p.message[..], b"hello");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
         // test sub
         // SUB <subject> <sid>\r\n
         let mut buf = BytesMut::from("SUB subject 5\r\n".as_bytes());
         let result = decoder.decode(&mut buf).unwrap().unwrap();
         println!("{:?}", result);
+        match result {
+            NatsProtocol::Sub(s) => {
+                assert_eq!(s.subject, "subject");
+                assert_eq!(s.queue, None);
+                assert_eq!(s.sid, "5");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
 
         // test sub
         // SUB <subject> <sid>\r\n
         let mut buf = BytesMut::from("SUB subject queue 5\r\n".as_bytes());
         let result = decoder.decode(&mut buf).unwrap().unwrap();
         println!("{:?}", result);
+        match result {
+            NatsProtocol::Sub(s) => {
+                assert_eq!(s.subject, "subject");
+                assert_eq!(s.queue.as_deref(), Some("queue"));
+                assert_eq!(s.sid, "5");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // test unsub
+        // UNSUB <sid> [max_msgs]\r\n
+        let mut buf = BytesMut::from("UNSUB 5\r\nUNSUB 6 10\r\n".as_bytes());
+        match decoder.decode(&mut buf).unwrap().unwrap() {
+            NatsProtocol::Unsub(u) => {
+                assert_eq!(u.sid, "5");
+                assert_eq!(u.max_msgs, None);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match decoder.decode(&mut buf).unwrap().unwrap() {
+            NatsProtocol::Unsub(u) => {
+                assert_eq!(u.sid, "6");
+                assert_eq!(u.max_msgs, Some(10));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // HPUB <subject> [reply-to] <#header bytes> <#total bytes>\r\n
+        // [headers]\r\n\r\n[payload]\r\n
+        let mut buf = BytesMut::from(
+            "HPUB subject reply 18 23\r\nNATS/1.0\r\nA: 1\r\n\r\nhello\r\n".as_bytes(),
+        );
+        match decoder.decode(&mut buf).unwrap().unwrap() {
+            NatsProtocol::Pub(p) => {
+                assert_eq!(p.channel, "subject");
+                assert_eq!(p.reply.as_deref(), Some("reply"));
+                let headers = Headers::parse(&p.headers.unwrap()).unwrap();
+                assert_eq!(headers.get("A"), Some("1"));
+                assert_eq!(&p.message[..], b"hello");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(buf.is_empty());
+        let mut buf = BytesMut::from("HPUB subject 24 23\r\n".as_bytes());
+        assert!(decoder.decode(&mut buf).is_err());
+        decoder.state = ParseState::OpStart;
+
+        // test connect
+        // CONNECT {["option_name":option_value],...}\r\n
+        let mut buf = BytesMut::from(
+            "CONNECT {\"verbose\":true,\"name\":\"test\",\"lang\":\"rust\"}\r\n".as_bytes(),
+        );
+        match decoder.decode(&mut buf).unwrap().unwrap() {
+            NatsProtocol::Connect(c) => {
+                assert!(c.verbose);
+                assert!(!c.pedantic);
+                assert!(c.echo);
+                assert_eq!(c.name.as_deref(), Some("test"));
+                assert_eq!(c.lang, "rust");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // test ping/pong
+        let mut buf = BytesMut::from("PING\r\nPONG\r\nPI".as_bytes());
+        let result = decoder.decode(&mut buf).unwrap().unwrap();
+        assert!(matches!(result, NatsProtocol::Ping(_)));
+        let result = decoder.decode(&mut buf).unwrap().unwrap();
+        assert!(matches!(result, NatsProtocol::Pong(_)));
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"NG\r\n");
+        let result = decoder.decode(&mut buf).unwrap().unwrap();
+        assert!(matches!(result, NatsProtocol::Ping(_)));
+
+        // test unknown operation
+        let mut buf = BytesMut::from("FOO bar\r\n".as_bytes());
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Err(Error::ProtocolError { op, .. }) if op == "FOO"
+        ));
+    }
+
+    #[test]
+    fn test_decode_syntax() {
+        let mut decoder = NatsMessageCodec::new();
+
+        // Any case, tabs and runs of spaces, bare `\n` after control lines.
+        let mut buf = BytesMut::from(
+            "pub\tfoo  reply \t 5\r\nhello\r\nSub foo\tq 1\nping\r\nPonG \r\n".as_bytes(),
+        );
+        match decoder.decode(&mut buf).unwrap().unwrap() {
+            NatsProtocol::Pub(p) => {
+                assert_eq!(p.channel, "foo");
+                assert_eq!(p.reply.as_deref(), Some("reply"));
+                assert_eq!(&p.message[..], b"hello");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match decoder.decode(&mut buf).unwrap().unwrap() {
+            NatsProtocol::Sub(s) => {
+                assert_eq!(s.subject, "foo");
+                assert_eq!(s.queue.as_deref(), Some("q"));
+                assert_eq!(s.sid, "1");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(NatsProtocol::Ping(_))
+        ));
+        assert!(matches!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(NatsProtocol::Pong(_))
+        ));
+        assert!(buf.is_empty());
+
+        // Unknown operations are refused before the line ends, and report
+        // where they start in the stream.
+        let mut buf = BytesMut::from("PING\r\nPINX".as_bytes());
+        decoder.decode(&mut buf).unwrap();
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Err(Error::ProtocolError { op, pos }) if op == "PINX" && pos == 58
+        ));
+
+        // The payload must be followed by `\r\n`.
+        let mut decoder = NatsMessageCodec::new();
+        let mut buf = BytesMut::from("PUB foo 5\r\nhello!\r\n".as_bytes());
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Err(Error::ProtocolError { op, pos }) if op == "PUB" && pos == 16
+        ));
+
+        // Wrong arguments.
+        for line in &[
+            "PING foo\r\n",
+            "SUB foo\r\n",
+            "UNSUB 1 x\r\n",
+            "PUB foo\r\n",
+        ] {
+            let mut decoder = NatsMessageCodec::new();
+            let mut buf = BytesMut::from(line.as_bytes());
+            assert!(
+                matches!(decoder.decode(&mut buf), Err(Error::ProtocolError { .. })),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_msg() {
+        let mut codec = NatsMessageCodec::new();
+        let mut buf = BytesMut::new();
+        let msg = Msg {
+            subject: Subject::from_static("foo.bar"),
+            sid: Bytes::from_static(b"9"),
+            reply: None,
+            headers: None,
+            payload: Bytes::from_static(b"hello"),
+        };
+        codec.encode(ServerOp::Msg(msg), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"MSG foo.bar 9 5\r\nhello\r\n");
+
+        let mut buf = BytesMut::new();
+        let msg = Msg {
+            subject: Subject::from_static("foo.bar"),
+            sid: Bytes::from_static(b"9"),
+            reply: Some(Subject::from_static("_INBOX.1")),
+            headers: None,
+            payload: Bytes::from_static(b"hello"),
+        };
+        codec.encode(ServerOp::Msg(msg), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"MSG foo.bar 9 _INBOX.1 5\r\nhello\r\n");
+
+        let mut buf = BytesMut::new();
+        let msg = Msg {
+            subject: Subject::from_static("_INBOX.1"),
+            sid: Bytes::from_static(b"9"),
+            reply: None,
+            headers: Some(Headers::with_status(503, None).to_bytes()),
+            payload: Bytes::new(),
+        };
+        codec.encode(ServerOp::Msg(msg), &mut buf).unwrap();
+        assert_eq!(
+            &buf[..],
+            b"HMSG _INBOX.1 9 16 16\r\nNATS/1.0 503\r\n\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_encode_server_ops() {
+        let mut codec = NatsMessageCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(ServerOp::Ping, &mut buf).unwrap();
+        codec.encode(ServerOp::Pong, &mut buf).unwrap();
+        codec.encode(ServerOp::Ok, &mut buf).unwrap();
+        codec
+            .encode(ServerOp::Err("Stale Connection".to_string()), &mut buf)
+            .unwrap();
+        assert_eq!(
+            &buf[..],
+            &b"PING\r\nPONG\r\n+OK\r\n-ERR 'Stale Connection'\r\n"[..]
+        );
+
+        let mut buf = BytesMut::new();
+        let info = ServerInfo::new("127.0.0.1", 4222, &ServerOptions::default());
+        codec
+            .encode(ServerOp::Info(info.clone()), &mut buf)
+            .unwrap();
+        assert!(buf.starts_with(b"INFO {"));
+        assert!(buf.ends_with(b"}\r\n"));
+        let json: serde_json::Value = serde_json::from_slice(&buf[5..buf.len() - 2]).unwrap();
+        assert_eq!(json["server_id"], info.server_id);
+        assert_eq!(json["port"], 4222);
+        assert_eq!(json["headers"], true);
+        assert_eq!(json["max_payload"], DEFAULT_MAX_PAYLOAD);
+        assert_eq!(json["max_control_line"], DEFAULT_MAX_CONTROL_LINE);
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let mut decoder = NatsMessageCodec::with_limits(8, 16);
+
+        // A payload up to the limit arriving in pieces.
+        let mut buf = BytesMut::from("PUB foo 8\r\nhel".as_bytes());
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"lo, w\r");
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"\n");
+        match decoder.decode(&mut buf).unwrap().unwrap() {
+            NatsProtocol::Pub(p) => assert_eq!(&p.message[..], b"hello, w"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(buf.is_empty());
+
+        // A larger payload is refused as soon as its size is known.
+        let mut buf = BytesMut::from("PUB foo 9\r\n".as_bytes());
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Err(Error::MaxPayloadViolation)
+        ));
+        let mut decoder = NatsMessageCodec::with_limits(8, 16);
+        let mut buf = BytesMut::from("HPUB foo 4 9\r\n".as_bytes());
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Err(Error::MaxPayloadViolation)
+        ));
+
+        // Control lines up to the limit, complete or not, are fine.
+        let mut decoder = NatsMessageCodec::with_limits(8, 16);
+        let mut buf = BytesMut::from("SUB foo.bar.ba".as_bytes());
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"z 1\r\n");
+        assert!(matches!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(NatsProtocol::Sub(_))
+        ));
+
+        // A longer line fails before its end arrives.
+        let mut buf = BytesMut::from("SUB foo.bar.baz.qux".as_bytes());
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b".quux");
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Err(Error::MaxControlLineExceeded)
+        ));
+        let mut decoder = NatsMessageCodec::with_limits(8, 16);
+        let mut buf = BytesMut::from("UNSUB WITHOUT ANY LINE END".as_bytes());
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Err(Error::MaxControlLineExceeded)
+        ));
+    }
+
+    /// Decode what a client sends, deliver it back as the server would and
+    /// check the wire bytes survive unchanged.
+    #[test]
+    fn test_round_trip() {
+        let mut codec = NatsMessageCodec::new();
+
+        let mut buf = BytesMut::new();
+        codec.encode(ServerOp::Ping, &mut buf).unwrap();
+        codec.encode(ServerOp::Pong, &mut buf).unwrap();
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(NatsProtocol::Ping(_))
+        ));
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(NatsProtocol::Pong(_))
+        ));
+
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"PUB foo 5\r\nhello\r\n", b"MSG foo 1 5\r\nhello\r\n"),
+            (b"PUB foo 0\r\n\r\n", b"MSG foo 1 0\r\n\r\n"),
+            (
+                b"PUB foo _INBOX.a 5\r\nhello\r\n",
+                b"MSG foo 1 _INBOX.a 5\r\nhello\r\n",
+            ),
+            (
+                b"HPUB foo 18 23\r\nNATS/1.0\r\nA: 1\r\n\r\nhello\r\n",
+                b"HMSG foo 1 18 23\r\nNATS/1.0\r\nA: 1\r\n\r\nhello\r\n",
+            ),
+            (
+                b"HPUB foo _INBOX.a 30 30\r\nNATS/1.0 503 No Responders\r\n\r\n\r\n",
+                b"HMSG foo 1 _INBOX.a 30 30\r\nNATS/1.0 503 No Responders\r\n\r\n\r\n",
+            ),
+        ];
+        for (input, output) in cases {
+            let mut buf = BytesMut::from(*input);
+            let publish = match codec.decode(&mut buf).unwrap() {
+                Some(NatsProtocol::Pub(p)) => p,
+                other => panic!("unexpected {:?}", other),
+            };
+            assert!(buf.is_empty());
+            let msg = Msg {
+                subject: publish.channel,
+                sid: Bytes::from_static(b"1"),
+                reply: publish.reply,
+                headers: publish.headers,
+                payload: publish.message,
+            };
+            codec.encode(ServerOp::Msg(msg), &mut buf).unwrap();
+            assert_eq!(&buf[..], *output);
+        }
     }
 }
@@ -1,29 +1,97 @@
 use bytes::Bytes;
 
-use crate::{connection::Connection, errors::Error, server::Db};
-
+use crate::{
+    connection::Connection, errors::Error, headers::Headers, server::Db, subject::Subject,
+};
 
 #[derive(Debug)]
 pub struct Publish {
-    pub channel: String,
+    pub channel: Subject,
+    /// Subject the receivers should send their replies to.
+    pub reply: Option<Subject>,
+    /// Header section sent with `HPUB`, as it was received.
+    pub headers: Option<Bytes>,
     pub size: usize,
     pub message: Bytes,
 }
 
+/// A published message as it is handed to the matching subscriptions. All
+/// fields share the buffer the message was read from, so cloning it for
+/// each subscription does not copy the subject, headers or payload.
+#[derive(Debug, Clone)]
+pub(crate) struct Message {
+    pub(crate) subject: Subject,
+    pub(crate) reply: Option<Subject>,
+    pub(crate) headers: Option<Bytes>,
+    pub(crate) payload: Bytes,
+}
+
+/// Status sent back to a requester when nobody is subscribed to the request
+/// subject.
+const NO_RESPONDERS: u16 = 503;
+
+impl Message {
+    /// A message telling the requester on `reply` that its request has no
+    /// responders.
+    fn no_responders(reply: Subject) -> Message {
+        Message {
+            subject: reply,
+            reply: None,
+            headers: Some(Headers::with_status(NO_RESPONDERS, None).to_bytes()),
+            payload: Bytes::new(),
+        }
+    }
+}
+
 impl Publish {
     /// Create a new `Publish` command which sends `message` on `channel`.
-    pub(crate) fn new(channel: impl ToString, size:usize, message: Bytes) -> Publish {
+    pub(crate) fn new(
+        channel: Subject,
+        reply: Option<Subject>,
+        headers: Option<Bytes>,
+        size: usize,
+        message: Bytes,
+    ) -> Publish {
         Publish {
-            channel: channel.to_string(),
+            channel,
+            reply,
+            headers,
             size,
             message,
         }
     }
 
     pub(crate) async fn apply(self, db: &Db, dst: &mut Connection) -> Result<(), Error> {
-        let _ = db.publish(&self.channel, self.message);
+        if self.headers.is_some() && !dst.opts.headers {
+            return Err(Error::HeadersNotSupported);
+        }
+        if dst.opts.pedantic {
+            self.channel.validate_literal()?;
+            if let Some(reply) = &self.reply {
+                reply.validate_literal()?;
+            }
+        }
+        let reply = self.reply.clone();
+        // With echo off the client does not get its own messages back.
+        let skip = (!dst.opts.echo).then_some(dst.cid);
+        let interested = db.publish(
+            Message {
+                subject: self.channel,
+                reply: self.reply,
+                headers: self.headers,
+                payload: self.message,
+            },
+            skip,
+        );
 
-        // TODO wirte response to the client.
-        Ok(())
+        // Let the requester fail fast instead of waiting for a timeout. The
+        // status only goes to its own subscription to the reply subject.
+        if interested == 0 && dst.opts.headers && dst.opts.no_responders {
+            if let Some(reply) = reply {
+                dst.subs.deliver(&Message::no_responders(reply));
+            }
+        }
+
+        dst.ok().await
     }
-}
\ No newline at end of file
+}
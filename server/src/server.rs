@@ -1,23 +1,60 @@
 use crate::errors::Error;
-use crate::{connection::Connection, protocol::NatsProtocol};
-use bytes::Bytes;
+use crate::{
+    auth, connection::Connection, info::ServerInfo, options::ServerOptions, outbound::Outbound,
+    process::ProcessFiles, protocol::ServerOp, publish::Message, shutdown::Shutdown, stats::Stats,
+    sublist::Sublist, subscribe::Subscription,
+};
 use futures_util::stream::StreamExt;
-use log::{error, info, trace};
+use log::{debug, error, info, trace, warn};
+use rand::RngExt;
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::broadcast,
+    sync::{broadcast, mpsc, watch, Notify},
+    task,
     time::{self, Duration},
 };
 
 use std::future::Future;
+
+/// How long `run` waits for the connections to finish writing once the
+/// server is shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lame duck mode does not wait less than this between closing clients, it
+/// closes more of them at once instead.
+const LAME_DUCK_MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Requests to a running server.
+#[derive(Debug, Clone)]
+pub enum Control {
+    /// Stop accepting clients, ask the connected ones to reconnect elsewhere
+    /// and close them gradually before shutting down.
+    LameDuck,
+    /// Switch to these options, reloaded from the configuration file. The
+    /// reload is rejected if an option that cannot change while the server
+    /// runs, such as the port, is different.
+    Reload(Box<ServerOptions>),
+}
+
 #[derive(Debug)]
 struct Listener {
     db: Db,
     listener: TcpListener,
+
+    /// Broadcasts the shutdown signal to every `Handler`. Dropping it is the
+    /// signal.
+    notify_shutdown: broadcast::Sender<()>,
+
+    /// Cloned into every connection task. Once all the clones are dropped,
+    /// the receiving end completes and every connection has been closed.
+    shutdown_complete_tx: mpsc::Sender<()>,
 }
 
 impl Listener {
@@ -25,13 +62,48 @@ impl Listener {
         trace!("accepting inbound connections");
         loop {
             let socket = self.accept().await?;
+            let mut conn = Connection::new(socket, &self.db);
+            let close = Arc::new(Notify::new());
+            let client = Client {
+                outbound: conn.outbound.clone(),
+                close: close.clone(),
+            };
+            if !self.db.add_client(conn.cid, client) {
+                warn!(
+                    "maximum connections of {} exceeded",
+                    self.db.options().max_connections
+                );
+                let shutdown_complete = self.shutdown_complete_tx.clone();
+                let info = self.db.info();
+                tokio::spawn(async move {
+                    // Clients expect the `INFO` block first, even when they
+                    // are turned away.
+                    let _ = conn.send(ServerOp::Info(info)).await;
+                    let _ = conn.reply_error(Error::MaxConnectionsExceeded).await;
+                    let _ = conn.close().await;
+                    drop(shutdown_complete);
+                });
+                continue;
+            }
             let mut handler = Handler {
                 db: self.db.clone(),
-                conn: Connection::new(socket),
+                conn,
+                shutdown: Shutdown::new(self.notify_shutdown.subscribe()),
+                close,
+                options: self.db.watch_options(),
+                _shutdown_complete: self.shutdown_complete_tx.clone(),
             };
 
             tokio::spawn(async move {
-                let _ = handler.run().await;
+                if let Err(err) = handler.run().await {
+                    info!("connection closed: {}", err);
+                }
+                // Whatever was queued before the loop ended, e.g. a final
+                // `-ERR`, is still written out.
+                if let Err(err) = handler.conn.close().await {
+                    info!("connection write failed: {}", err);
+                }
+                handler.db.remove_client(handler.conn.cid);
             });
         }
     }
@@ -61,53 +133,268 @@ impl Listener {
 struct Handler {
     conn: Connection,
     db: Db,
+
+    /// Tells the handler the server is shutting down. The connection is then
+    /// closed once everything queued for the client is written.
+    shutdown: Shutdown,
+
+    /// Closes this connection alone, the same way as `shutdown`.
+    close: Arc<Notify>,
+
+    /// Notified when the server reloads its options.
+    options: watch::Receiver<Arc<ServerOptions>>,
+
+    /// Not used directly, dropped with the handler to signal that the
+    /// connection is closed.
+    _shutdown_complete: mpsc::Sender<()>,
 }
 
 impl Handler {
     /// Process a single connection.
+    ///
+    /// The server speaks first: an `INFO` block is written as soon as the
+    /// connection is accepted, after which the client sends `CONNECT`, in
+    /// time if it has to authenticate. From then this loop serves the
+    /// client's operations and the keep-alive timer. Messages published to
+    /// the client's subscriptions do not go through here, they are queued
+    /// directly on the connection's outbound queue.
+    ///
+    /// A client found to be a slow consumer anywhere along the way, even
+    /// while a reply waits for room in its queue, is closed as one.
     async fn run(&mut self) -> Result<(), Error> {
-        loop {
-            if let Some(next) = self.conn.stream.next().await {
-                next?.apply(&self.db, &mut self.conn).await?;
-            } else {
-                info!("connect closed");
-                break;
+        match self.serve().await {
+            Err(Error::SlowConsumer) => self.slow_consumer().await,
+            res => res,
+        }
+    }
+
+    /// The loop of `run`, until the client leaves or has to be closed.
+    async fn serve(&mut self) -> Result<(), Error> {
+        self.conn.send(ServerOp::Info(self.db.info())).await?;
+        let mut opts = self.options.borrow().clone();
+        let auth_required = opts.authorization.is_some();
+        let auth_timeout = time::sleep(opts.auth_timeout);
+        tokio::pin!(auth_timeout);
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                next = self.conn.reader.next() => {
+                    let res = match next {
+                        Some(Ok(protocol)) => {
+                            trace!("<- {:?}", protocol);
+                            protocol.apply(&self.db, &mut self.conn).await
+                        }
+                        Some(Err(err)) => {
+                            // The codec cannot recover from a decode error.
+                            self.conn.reply_error(err).await?;
+                            break;
+                        }
+                        None => {
+                            info!("connect closed");
+                            break;
+                        }
+                    };
+                    if let Err(err) = res {
+                        self.conn.reply_error(err).await?;
+                    }
+                    // Decoding from the read buffer never yields. Give the
+                    // writers of the subscribers a chance to run while a
+                    // client publishes a burst.
+                    task::coop::consume_budget().await;
+                }
+                _ = self.conn.outbound.slow_consumer() => {
+                    return Err(Error::SlowConsumer);
+                }
+                _ = self.conn.outbound.closed() => {
+                    // A writer that went past its write deadline stops
+                    // after marking the client a slow consumer.
+                    if self.conn.outbound.is_slow_consumer() {
+                        return Err(Error::SlowConsumer);
+                    }
+                    // The writer stopped, the socket can no longer be written.
+                    return Err(Error::ConnectionClosed);
+                }
+                _ = self.conn.ping_timer.tick() => {
+                    if let Err(err) = self.conn.ping().await {
+                        self.conn.reply_error(err).await?;
+                    }
+                }
+                _ = self.shutdown.recv() => {
+                    debug!("closing connection, server shutting down");
+                }
+                _ = self.close.notified() => {
+                    debug!("closing connection, server in lame duck mode");
+                    break;
+                }
+                _ = &mut auth_timeout, if auth_required && !self.conn.connected => {
+                    info!("authentication timeout");
+                    self.conn.reply_error(Error::AuthenticationTimeout).await?;
+                }
+                Ok(()) = self.options.changed() => {
+                    let reloaded = self.options.borrow_and_update().clone();
+                    self.reload(&opts, &reloaded).await?;
+                    opts = reloaded;
+                }
             }
         }
         Ok(())
     }
+
+    /// Apply the options reloaded while the client is connected. A client
+    /// that authenticated is checked again if the authorization changed, and
+    /// closed if it no longer passes.
+    async fn reload(
+        &mut self,
+        old: &ServerOptions,
+        opts: &Arc<ServerOptions>,
+    ) -> Result<(), Error> {
+        // Only advertise the new limits once they apply.
+        if self.conn.reload(opts) {
+            self.conn.send(ServerOp::Info(self.db.info())).await?;
+        }
+        if self.conn.connected
+            && old.authorization != opts.authorization
+            && !auth::authorize(opts.clone(), self.conn.opts.clone()).await
+        {
+            info!(
+                "authorization revoked name:{:?} user:{:?}",
+                self.conn.opts.name, self.conn.opts.user
+            );
+            return self.conn.reply_error(Error::AuthorizationViolation).await;
+        }
+        Ok(())
+    }
+
+    /// Close a client that does not keep up with its messages.
+    async fn slow_consumer(&mut self) -> Result<(), Error> {
+        let err = Error::SlowConsumer;
+        let stats = self.db.stats();
+        stats.slow_consumer();
+        warn!(
+            "slow consumer, closing connection with {} subscriptions ({} slow consumers so far)",
+            self.conn.subs.len(),
+            stats.slow_consumers()
+        );
+        self.conn.abort(&err).await?;
+        Err(err)
+    }
+}
+
+/// Serve clients accepted on `listener` until `shutdown` completes.
+///
+/// On shutdown the server stops accepting clients and tells every
+/// connection to close. It returns once they have written out what was
+/// queued for their clients, or after a timeout.
+pub async fn run(
+    listener: TcpListener,
+    opts: ServerOptions,
+    shutdown: impl Future,
+) -> Result<(), Error> {
+    let (_control_tx, control) = mpsc::channel(1);
+    run_with_control(listener, opts, shutdown, control).await
 }
 
-pub async fn run(listener: TcpListener, shutdown: impl Future) -> Result<(), Error> {
+/// Like `run`, also serving the requests received on `control`.
+pub async fn run_with_control(
+    listener: TcpListener,
+    opts: ServerOptions,
+    shutdown: impl Future,
+    mut control: mpsc::Receiver<Control>,
+) -> Result<(), Error> {
+    let addr = listener.local_addr()?;
+    let info = ServerInfo::new(addr.ip(), addr.port(), &opts);
+    // Removed when the server has stopped.
+    let _files = ProcessFiles::write(&opts, addr)?;
+    let (notify_shutdown, _) = broadcast::channel(1);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
     let mut server = Listener {
         listener,
-        db: Db::new(),
+        db: Db::new(info, opts),
+        notify_shutdown,
+        shutdown_complete_tx,
     };
-    info!("server run:{}", 1234);
+    info!("listening for client connections on {}", addr);
+    if let Some(port) = server.db.options().http_port {
+        warn!("http monitoring is not supported, ignoring port {}", port);
+    }
 
-    tokio::select! {
-        res = server.run() => {
-            if let Err(err) = res {
-                error!("server err {:?}", err);
+    tokio::pin!(shutdown);
+    let lame_duck = loop {
+        tokio::select! {
+            res = server.run() => {
+                if let Err(err) = res {
+                    error!("server err {:?}", err);
+                }
+                break false;
             }
+            _ = &mut shutdown => {
+                info!("shutting down");
+                break false;
+            }
+            Some(request) = control.recv() => match request {
+                Control::LameDuck => break true,
+                Control::Reload(opts) => {
+                    if let Err(err) = server.db.reload(*opts) {
+                        error!("config reload failed: {}", err);
+                    }
+                }
+            },
         }
-        _ = shutdown => {
-            info!("shutting down");
+    };
+
+    // Stop accepting clients first.
+    let Listener {
+        db,
+        listener,
+        notify_shutdown,
+        shutdown_complete_tx,
+    } = server;
+    drop(listener);
+
+    if lame_duck {
+        info!("entering lame duck mode, stopped accepting new clients");
+        tokio::select! {
+            _ = db.lame_duck() => {}
+            _ = &mut shutdown => {
+                info!("shutting down, leaving lame duck mode early");
+            }
         }
     }
+
+    // Dropping the sender notifies every `Handler`, and dropping the
+    // listener's own completion sender leaves only the connections' ones.
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+
+    if time::timeout(SHUTDOWN_TIMEOUT, shutdown_complete_rx.recv())
+        .await
+        .is_err()
+    {
+        warn!("connections still open after {:?}", SHUTDOWN_TIMEOUT);
+    }
     Ok(())
-    //server.run().await
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct Db {
-    /// Handle to shared state. The background task will also have an
-    /// `Arc<Shared>`.
+    /// Handle to shared state. Every connection task holds a clone.
     shared: Arc<Shared>,
 }
 
 #[derive(Debug)]
 struct Shared {
+    /// The `INFO` sent to every new client.
+    info: Mutex<ServerInfo>,
+
+    /// Options shared by all connections. Replaced when the server reloads
+    /// its configuration, which the connections watch for.
+    opts: watch::Sender<Arc<ServerOptions>>,
+
+    /// Counters updated by the connections.
+    stats: Stats,
+
+    /// Id given to the next accepted client.
+    next_cid: AtomicU64,
+
     /// The shared state is guarded by a mutex. This is a `std::sync::Mutex` and
     /// not a Tokio mutex. This is because there are no asynchronous operations
     /// being performed while holding the mutex. Additionally, the critical
@@ -117,69 +404,694 @@ struct Shared {
 
 #[derive(Debug)]
 struct State {
-    /// The pub/sub key-space.
-    pub_sub: HashMap<String, broadcast::Sender<Bytes>>,
-    // shutdown: bool,
+    /// Index of every subscription, keyed by subject.
+    subs: Sublist,
+    /// Every connected client, keyed by client id.
+    clients: HashMap<u64, Client>,
+}
+
+/// What the server keeps of a connected client to reach it from outside its
+/// connection task.
+#[derive(Debug, Clone)]
+struct Client {
+    outbound: Outbound,
+    /// Closes the connection.
+    close: Arc<Notify>,
 }
 
 impl Db {
-    /// Create a new, empty, `Db` instance. Allocates shared state and spawns a
-    /// background task to manage key expiration.
-    pub(crate) fn new() -> Db {
+    /// Create a new `Db`, with no subscriptions or clients yet, serving
+    /// `info` to new clients and starting with the options `opts`.
+    pub(crate) fn new(info: ServerInfo, opts: ServerOptions) -> Db {
         let shared = Arc::new(Shared {
+            info: Mutex::new(info),
+            opts: watch::Sender::new(Arc::new(opts)),
+            stats: Stats::new(),
+            next_cid: AtomicU64::new(1),
             state: Mutex::new(State {
-                pub_sub: HashMap::new(),
-                // shutdown: false,
+                subs: Sublist::new(),
+                clients: HashMap::new(),
             }),
         });
 
         Db { shared }
     }
 
-    /// Returns a `Receiver` for the requested channel.
+    /// Returns the `INFO` to send to a newly accepted client.
+    pub(crate) fn info(&self) -> ServerInfo {
+        self.shared.info.lock().unwrap().clone()
+    }
+
+    /// Returns the options currently in use.
+    pub(crate) fn options(&self) -> Arc<ServerOptions> {
+        self.shared.opts.borrow().clone()
+    }
+
+    /// Returns a receiver notified each time the options are reloaded.
+    fn watch_options(&self) -> watch::Receiver<Arc<ServerOptions>> {
+        self.shared.opts.subscribe()
+    }
+
+    /// Switch to `opts`, reloaded from the configuration file. Fails without
+    /// changing anything if an option that cannot be reloaded changed.
     ///
-    /// The returned `Receiver` is used to receive values broadcast by `PUB`
-    /// commands.
-    pub(crate) fn subscribe(&self, key: String) -> broadcast::Receiver<Bytes> {
-        use std::collections::hash_map::Entry;
+    /// New clients get all the new options, the connections of the others
+    /// apply what they can, see `Connection::reload`.
+    fn reload(&self, opts: ServerOptions) -> Result<(), Error> {
+        let changes = self.options().changes(&opts);
+        let fixed: Vec<&str> = changes
+            .iter()
+            .copied()
+            .filter(|name| !ServerOptions::is_reloadable(name))
+            .collect();
+        if !fixed.is_empty() {
+            return Err(Error::ConfigError(format!(
+                "reload not supported for {}, restart the server to change it",
+                fixed.join(", ")
+            )));
+        }
+        if changes.is_empty() {
+            info!("reloaded configuration, nothing changed");
+            return Ok(());
+        }
+
+        if changes.contains(&"debug") || changes.contains(&"trace") {
+            log::set_max_level(opts.log_level());
+        }
+        {
+            let mut info = self.shared.info.lock().unwrap();
+            info.max_payload = opts.max_payload;
+            info.max_control_line = opts.max_control_line;
+            info.auth_required = opts.authorization.is_some();
+        }
+        self.shared.opts.send_replace(Arc::new(opts));
+        info!("reloaded configuration, changed {}", changes.join(", "));
+        Ok(())
+    }
+
+    /// Returns the server wide counters.
+    pub(crate) fn stats(&self) -> &Stats {
+        &self.shared.stats
+    }
 
-        // Acquire the mutex
+    /// Returns a new id, unique among the clients of this server.
+    pub(crate) fn next_client_id(&self) -> u64 {
+        self.shared.next_cid.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Register a newly connected client. Returns `false`, without
+    /// registering it, if `max_connections` clients are already connected.
+    fn add_client(&self, cid: u64, client: Client) -> bool {
         let mut state = self.shared.state.lock().unwrap();
+        if state.clients.len() >= self.options().max_connections {
+            return false;
+        }
+        state.clients.insert(cid, client);
+        true
+    }
 
-        match state.pub_sub.entry(key) {
-            Entry::Occupied(e) => e.get().subscribe(),
-            Entry::Vacant(e) => {
-                // No broadcast channel exists yet, so create one.
-                //
-                // The channel is created with a capacity of `1024` messages. A
-                // message is stored in the channel until **all** subscribers
-                // have seen it. This means that a slow subscriber could result
-                // in messages being held indefinitely.
-                //
-                // When the channel's capacity fills up, publishing will result
-                // in old messages being dropped. This prevents slow consumers
-                // from blocking the entire system.
-                let (tx, rx) = broadcast::channel(1024);
-                e.insert(tx);
-                rx
+    /// Forget a client registered with `add_client`.
+    fn remove_client(&self, cid: u64) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.clients.remove(&cid);
+    }
+
+    /// Tell every client the server is in lame duck mode, then close them
+    /// spread over `lame_duck_duration`, after `lame_duck_grace_period`.
+    async fn lame_duck(&self) {
+        let opts = self.options();
+        let clients: Vec<Client> = {
+            let state = self.shared.state.lock().unwrap();
+            state.clients.values().cloned().collect()
+        };
+        let mut info = self.info();
+        info.ldm = true;
+        for client in &clients {
+            client.outbound.try_send(ServerOp::Info(info.clone()));
+        }
+        if clients.is_empty() {
+            return;
+        }
+
+        time::sleep(opts.lame_duck_grace_period).await;
+        let period = opts
+            .lame_duck_duration
+            .saturating_sub(opts.lame_duck_grace_period);
+        info!(
+            "lame duck mode closing {} clients over {:?}",
+            clients.len(),
+            period
+        );
+        // Close batches of clients at a steady pace, so they do not all
+        // reconnect to the other servers at once.
+        let ticks = (period.as_millis() / LAME_DUCK_MIN_INTERVAL.as_millis()).max(1);
+        let ticks = ticks.min(clients.len() as u128) as usize;
+        let batch = clients.len().div_ceil(ticks);
+        let interval = period / ticks as u32;
+        for (i, batch) in clients.chunks(batch).enumerate() {
+            if i > 0 {
+                time::sleep(interval).await;
+            }
+            for client in batch {
+                client.close.notify_one();
+            }
+        }
+    }
+
+    /// Register `sub` so it receives messages published to matching
+    /// subjects. Fails if the subject is not a valid subscription subject.
+    pub(crate) fn subscribe(&self, sub: Arc<Subscription>) -> Result<(), Error> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.subs.insert(sub)
+    }
+
+    /// Remove a subscription registered with `subscribe`.
+    pub(crate) fn unsubscribe(&self, sub: &Arc<Subscription>) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.subs.remove(sub);
+    }
+
+    /// Publish a message to the subject. Every plain subscription gets a copy,
+    /// and each queue group gets one copy delivered to a randomly chosen
+    /// member. The subscriptions of client `skip`, if any, are left out.
+    /// Returns the number of plain subscriptions and queue groups interested
+    /// in the message, whether or not they could take it.
+    pub(crate) fn publish(&self, msg: Message, skip: Option<u64>) -> usize {
+        // Only hold the lock while looking up the subscriptions.
+        let result = self.shared.state.lock().unwrap().subs.matches(&msg.subject);
+
+        let mut interested = 0;
+        for sub in result.psubs.iter().filter(|sub| Some(sub.cid) != skip) {
+            interested += 1;
+            self.deliver(sub, &msg);
+        }
+
+        for group in &result.qsubs {
+            if group.iter().all(|sub| Some(sub.cid) == skip) {
+                continue;
+            }
+            interested += 1;
+            // Start at a random member and fall back to the next ones if it
+            // cannot take the message.
+            let start = rand::rng().random_range(0..group.len());
+            for i in 0..group.len() {
+                let sub = &group[(start + i) % group.len()];
+                if Some(sub.cid) != skip && self.deliver(sub, &msg) {
+                    break;
+                }
+            }
+        }
+        interested
+    }
+
+    /// Deliver `msg` to `sub`, removing the subscription once it reached its
+    /// auto-unsubscribe limit.
+    fn deliver(&self, sub: &Arc<Subscription>, msg: &Message) -> bool {
+        let delivered = sub.deliver(msg);
+        if delivered && sub.is_done() {
+            self.unsubscribe(sub);
+        }
+        delivered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Authorization, outbound::Outbound, subject::Subject};
+    use bytes::Bytes;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        sync::oneshot,
+    };
+
+    /// Connect to `addr`, send `ops` and wait until they were processed.
+    async fn client(addr: std::net::SocketAddr, ops: &[u8]) -> BufReader<TcpStream> {
+        let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("INFO "));
+        conn.get_mut().write_all(ops).await.unwrap();
+        conn.get_mut().write_all(b"PING\r\n").await.unwrap();
+        line.clear();
+        conn.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "PONG\r\n");
+        conn
+    }
+
+    #[test]
+    fn test_publish_skips_client() {
+        let opts = ServerOptions::default();
+        let db = Db::new(ServerInfo::new("127.0.0.1", 4222, &opts), opts);
+        let (outbound, mut rx) = Outbound::channel(16, 1024);
+        for cid in [1, 2] {
+            let sub = Subscription::new(
+                cid,
+                Subject::from("foo"),
+                None,
+                cid,
+                false,
+                outbound.clone(),
+            );
+            db.subscribe(Arc::new(sub)).unwrap();
+        }
+        let msg = Message {
+            subject: Subject::from("foo"),
+            reply: None,
+            headers: None,
+            payload: Bytes::from_static(b"hi"),
+        };
+
+        assert_eq!(db.publish(msg.clone(), None), 2);
+        assert_eq!(db.publish(msg, Some(1)), 1);
+        let sids: Vec<Bytes> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|op| match op {
+                ServerOp::Msg(msg) => msg.sid,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(sids, ["1", "2", "2"]);
+    }
+
+    #[test]
+    fn test_publish_queue_groups() {
+        let opts = ServerOptions::default();
+        let db = Db::new(ServerInfo::new("127.0.0.1", 4222, &opts), opts);
+        // One member per client, each with its own queue. Nothing writes the
+        // messages out, so the pending bytes add up.
+        let mut members = Vec::new();
+        for cid in 1..=3 {
+            let (outbound, rx) = Outbound::channel(64, usize::MAX);
+            let sub = Subscription::new(
+                cid,
+                Subject::from("foo"),
+                Some("workers".to_string()),
+                cid,
+                false,
+                outbound.clone(),
+            );
+            db.subscribe(Arc::new(sub)).unwrap();
+            members.push((outbound, rx));
+        }
+        let msg = Message {
+            subject: Subject::from("foo"),
+            reply: None,
+            headers: None,
+            payload: Bytes::from_static(b"hi"),
+        };
+        let received = |members: &mut Vec<(Outbound, mpsc::Receiver<ServerOp>)>| {
+            members
+                .iter_mut()
+                .map(|(_, rx)| std::iter::from_fn(|| rx.try_recv().ok()).count())
+                .collect::<Vec<_>>()
+        };
+
+        // Each message goes to a single member of the group.
+        for _ in 0..30 {
+            assert_eq!(db.publish(msg.clone(), None), 1);
+        }
+        assert_eq!(received(&mut members).iter().sum::<usize>(), 30);
+
+        // The publishing client's own member is skipped.
+        for _ in 0..30 {
+            assert_eq!(db.publish(msg.clone(), Some(2)), 1);
+        }
+        let counts = received(&mut members);
+        assert_eq!(counts[1], 0);
+        assert_eq!(counts.iter().sum::<usize>(), 30);
+
+        // A member that cannot take the message is passed over.
+        while members[0].0.try_send(ServerOp::Ok) {}
+        for _ in 0..30 {
+            assert_eq!(db.publish(msg.clone(), None), 1);
+        }
+        let counts = received(&mut members);
+        // Only what filled the queue.
+        assert_eq!(counts[0], 64);
+        assert_eq!(counts[1] + counts[2], 30);
+
+        // A group made only of the publishing client's members is not
+        // interested at all.
+        let (outbound, _rx) = Outbound::channel(64, usize::MAX);
+        let sub = Subscription::new(
+            4,
+            Subject::from("bar"),
+            Some("q".into()),
+            1,
+            false,
+            outbound,
+        );
+        db.subscribe(Arc::new(sub)).unwrap();
+        let msg = Message {
+            subject: Subject::from("bar"),
+            ..msg
+        };
+        assert_eq!(db.publish(msg, Some(4)), 0);
+    }
+
+    #[tokio::test]
+    async fn test_no_responders() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(run(
+            listener,
+            ServerOptions::default(),
+            std::future::pending::<()>(),
+        ));
+
+        // Another client listening on the same inbox does not get the status.
+        let mut other = client(addr, b"CONNECT {}\r\nSUB inbox.> 1\r\n").await;
+        let mut requester = client(
+            addr,
+            b"CONNECT {\"headers\":true,\"no_responders\":true}\r\nSUB inbox.1 1\r\n",
+        )
+        .await;
+        requester
+            .get_mut()
+            .write_all(b"PUB service inbox.1 2\r\nhi\r\n")
+            .await
+            .unwrap();
+        let mut msg = vec![0; 36];
+        requester.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, b"HMSG inbox.1 1 16 16\r\nNATS/1.0 503\r\n");
+
+        other.get_mut().write_all(b"PING\r\n").await.unwrap();
+        let mut line = String::new();
+        other.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "PONG\r\n");
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer_stats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let opts = ServerOptions {
+            max_pending: 64 * 1024,
+            ..ServerOptions::default()
+        };
+        let db = Db::new(ServerInfo::new(addr.ip(), addr.port(), &opts), opts);
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _shutdown_complete_rx) = mpsc::channel(1);
+        let mut server = Listener {
+            db: db.clone(),
+            listener,
+            notify_shutdown,
+            shutdown_complete_tx,
+        };
+        let server = tokio::spawn(async move { server.run().await });
+
+        // The subscriber never reads, so its messages pile up once the
+        // socket buffers are full.
+        let mut sub = client(addr, b"CONNECT {}\r\nSUB foo 1\r\n").await;
+        let mut publisher = client(addr, b"CONNECT {}\r\n").await;
+        let payload = vec![b'x'; 64 * 1024];
+        for _ in 0..1024 {
+            if db.stats().slow_consumers() > 0 {
+                break;
             }
+            publisher
+                .get_mut()
+                .write_all(format!("PUB foo {}\r\n", payload.len()).as_bytes())
+                .await
+                .unwrap();
+            publisher.get_mut().write_all(&payload).await.unwrap();
+            publisher.get_mut().write_all(b"\r\n").await.unwrap();
+            task::yield_now().await;
+        }
+        let counted = async {
+            while db.stats().slow_consumers() == 0 {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(5), counted)
+            .await
+            .unwrap();
+        assert_eq!(db.stats().slow_consumers(), 1);
+
+        // Whatever was already written arrives, then the error.
+        let mut received = Vec::new();
+        sub.read_to_end(&mut received).await.unwrap();
+        assert!(received.ends_with(b"-ERR 'Slow Consumer'\r\n"));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(run(listener, ServerOptions::default(), stopped));
+
+        let mut sub = client(addr, b"CONNECT {}\r\nSUB foo 1\r\n").await;
+        let mut publish = Vec::new();
+        for _ in 0..100 {
+            publish.extend_from_slice(b"PUB foo 5\r\nhello\r\n");
+        }
+        let _publisher = client(addr, &publish).await;
+
+        // The subscriber has not read anything yet, its messages are still
+        // queued or in flight when the server shuts down.
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        let mut received = Vec::new();
+        sub.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"MSG foo 1 5\r\nhello\r\n".repeat(100));
+    }
+
+    #[tokio::test]
+    async fn test_lame_duck() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let opts = ServerOptions {
+            lame_duck_grace_period: Duration::from_millis(50),
+            lame_duck_duration: Duration::from_millis(200),
+            ..ServerOptions::default()
+        };
+        let (control_tx, control) = mpsc::channel(1);
+        let server = tokio::spawn(run_with_control(
+            listener,
+            opts,
+            std::future::pending::<()>(),
+            control,
+        ));
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(client(addr, b"CONNECT {}\r\n").await);
+        }
+        control_tx.send(Control::LameDuck).await.unwrap();
+
+        for client in &mut clients {
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            assert!(line.starts_with("INFO ") && line.contains("\"ldm\":true"));
+        }
+        for client in &mut clients {
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        }
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (control_tx, control) = mpsc::channel(1);
+        let server = tokio::spawn(run_with_control(
+            listener,
+            ServerOptions::default(),
+            std::future::pending::<()>(),
+            control,
+        ));
+        let mut conn = client(addr, b"CONNECT {}\r\n").await;
+
+        // The port cannot change, nothing is applied.
+        let opts = ServerOptions {
+            port: 4333,
+            max_payload: 8,
+            ..ServerOptions::default()
+        };
+        control_tx
+            .send(Control::Reload(Box::new(opts)))
+            .await
+            .unwrap();
+        let opts = ServerOptions {
+            max_payload: 4,
+            ..ServerOptions::default()
+        };
+        control_tx
+            .send(Control::Reload(Box::new(opts)))
+            .await
+            .unwrap();
+
+        // The connected client is told about the new limit, which applies to
+        // it from then on.
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("INFO ") && line.contains("\"max_payload\":4,"));
+        conn.get_mut()
+            .write_all(b"PUB foo 5\r\nhello\r\n")
+            .await
+            .unwrap();
+        line.clear();
+        conn.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "-ERR 'Maximum Payload Violation'\r\n");
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        line.clear();
+        conn.read_line(&mut line).await.unwrap();
+        assert!(line.contains("\"max_payload\":4,"));
+
+        drop(control_tx);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_verbose_and_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(run(
+            listener,
+            ServerOptions::default(),
+            std::future::pending::<()>(),
+        ));
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("INFO "));
+        // Each operation is acknowledged, and an invalid subject does not
+        // close the connection.
+        for (op, reply) in [
+            (&b"CONNECT {\"verbose\":true}\r\n"[..], "+OK\r\n"),
+            (b"SUB foo 1\r\n", "+OK\r\n"),
+            (b"PUB bar 2\r\nhi\r\n", "+OK\r\n"),
+            (b"UNSUB 1\r\n", "+OK\r\n"),
+            (b"SUB foo..bar 2\r\n", "-ERR 'Invalid Subject'\r\n"),
+            (b"PING\r\n", "PONG\r\n"),
+        ] {
+            conn.get_mut().write_all(op).await.unwrap();
+            line.clear();
+            conn.read_line(&mut line).await.unwrap();
+            assert_eq!(line, reply);
+        }
+        // Any other error does.
+        conn.get_mut().write_all(b"FOO\r\n").await.unwrap();
+        let mut rest = String::new();
+        conn.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "-ERR 'Unknown Protocol Operation'\r\n");
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stale_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let opts = ServerOptions {
+            ping_interval: Duration::from_millis(50),
+            max_pings_out: 1,
+            ..ServerOptions::default()
+        };
+        let server = tokio::spawn(run(listener, opts, std::future::pending::<()>()));
+
+        let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("INFO "));
+        conn.get_mut().write_all(b"CONNECT {}\r\n").await.unwrap();
+        // Answering each `PING` keeps the client connected.
+        for _ in 0..3 {
+            line.clear();
+            conn.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "PING\r\n");
+            conn.get_mut().write_all(b"PONG\r\n").await.unwrap();
         }
+        // Until it stops answering.
+        let mut rest = String::new();
+        conn.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "PING\r\n-ERR 'Stale Connection'\r\n");
+
+        server.abort();
     }
 
-    /// Publish a message to the channel. Returns the number of subscribers
-    /// listening on the channel.
-    pub(crate) fn publish(&self, key: &str, value: Bytes) -> usize {
-        let state = self.shared.state.lock().unwrap();
+    #[tokio::test]
+    async fn test_max_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let opts = ServerOptions {
+            max_connections: 1,
+            ..ServerOptions::default()
+        };
+        let server = tokio::spawn(run(listener, opts, std::future::pending::<()>()));
+
+        let _first = client(addr, b"CONNECT {}\r\n").await;
+        let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("INFO "));
+        let mut rest = String::new();
+        conn.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "-ERR 'maximum connections exceeded'\r\n");
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_authorization() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let opts = ServerOptions {
+            authorization: Some(Authorization::Token("s3cr3t".into())),
+            auth_timeout: Duration::from_millis(100),
+            ..ServerOptions::default()
+        };
+        let (control_tx, control) = mpsc::channel(1);
+        let server = tokio::spawn(run_with_control(
+            listener,
+            opts.clone(),
+            std::future::pending::<()>(),
+            control,
+        ));
+
+        // Each client is closed after the error.
+        for (ops, err) in [
+            (
+                &b"CONNECT {\"auth_token\":\"wrong\"}\r\n"[..],
+                "Authorization Violation",
+            ),
+            (b"PUB foo 2\r\nhi\r\n", "Authorization Violation"),
+            (b"", "Authentication Timeout"),
+        ] {
+            let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+            let mut line = String::new();
+            conn.read_line(&mut line).await.unwrap();
+            assert!(line.contains("\"auth_required\":true"));
+            conn.get_mut().write_all(ops).await.unwrap();
+            let mut rest = String::new();
+            conn.read_to_string(&mut rest).await.unwrap();
+            assert_eq!(rest, format!("-ERR '{}'\r\n", err));
+        }
+
+        let mut conn = client(addr, b"CONNECT {\"auth_token\":\"s3cr3t\"}\r\n").await;
+        // A reload changing the token closes the clients using the old one.
+        let opts = ServerOptions {
+            authorization: Some(Authorization::Token("n3w".into())),
+            ..opts
+        };
+        control_tx
+            .send(Control::Reload(Box::new(opts)))
+            .await
+            .unwrap();
+        let mut rest = String::new();
+        conn.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "-ERR 'Authorization Violation'\r\n");
 
-        state
-            .pub_sub
-            .get(key)
-            // On a successful message send on the broadcast channel, the number
-            // of subscribers is returned. An error indicates there are no
-            // receivers, in which case, `0` should be returned.
-            .map(|tx| tx.send(value).unwrap_or(0))
-            // If there is no entry for the channel key, then there are no
-            // subscribers. In this case, return `0`.
-            .unwrap_or(0)
+        drop(control_tx);
+        server.abort();
     }
 }
@@ -0,0 +1,23 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters kept over the lifetime of the server.
+#[derive(Debug, Default)]
+pub struct Stats {
+    slow_consumers: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    /// Count a client closed for not keeping up with its messages.
+    pub fn slow_consumer(&self) {
+        self.slow_consumers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of clients closed as slow consumers.
+    pub fn slow_consumers(&self) -> u64 {
+        self.slow_consumers.load(Ordering::Relaxed)
+    }
+}
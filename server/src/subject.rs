@@ -0,0 +1,146 @@
+use bytes::Bytes;
+use std::{fmt, ops::Deref};
+
+use crate::{
+    errors::Error,
+    sublist::{FWC, PWC, TSEP},
+};
+
+/// A subject, sharing the bytes of the buffer it was read from.
+///
+/// Cloning a `Subject` only bumps a reference count, so a published subject
+/// can be handed to every matching subscription without copying it.
+///
+/// The bytes are always valid UTF-8: `from_bytes` checks them, and every
+/// other constructor starts from a `str`.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Subject(Bytes);
+
+impl Subject {
+    /// Wrap `bytes`, checking that they are a non-empty UTF-8 string.
+    pub fn from_bytes(bytes: Bytes) -> Result<Subject, Error> {
+        std::str::from_utf8(&bytes)?;
+        if bytes.is_empty() {
+            return Err(Error::InvalidSubject);
+        }
+        Ok(Subject(bytes))
+    }
+
+    pub fn from_static(subject: &'static str) -> Subject {
+        Subject(Bytes::from_static(subject.as_bytes()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: the bytes are UTF-8, as every constructor ensures (see
+        // the type's documentation), and `Bytes` is immutable.
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+
+    /// Returns the underlying bytes, without copying them.
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.0
+    }
+
+    /// Check the syntax of a subscription subject: no token is empty or
+    /// contains whitespace, and `>` can only be the last token.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut tokens = self.split(TSEP).peekable();
+        while let Some(token) = tokens.next() {
+            if !is_valid_name(token) || (token == FWC && tokens.peek().is_some()) {
+                return Err(Error::InvalidSubject);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the syntax of a subject messages are published to. On top of
+    /// `validate`, it must not contain wildcards.
+    pub fn validate_literal(&self) -> Result<(), Error> {
+        self.validate()?;
+        if !self.is_literal() {
+            return Err(Error::InvalidSubject);
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if no token is a wildcard.
+    pub fn is_literal(&self) -> bool {
+        self.split(TSEP).all(|token| token != PWC && token != FWC)
+    }
+}
+
+/// Returns `true` if `name` can be used as a subject token, queue name or
+/// sid: it is not empty and has no whitespace.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains(char::is_whitespace)
+}
+
+impl Deref for Subject {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<String> for Subject {
+    fn from(subject: String) -> Subject {
+        Subject(Bytes::from(subject))
+    }
+}
+
+impl From<&str> for Subject {
+    fn from(subject: &str) -> Subject {
+        Subject(Bytes::copy_from_slice(subject.as_bytes()))
+    }
+}
+
+impl PartialEq<str> for Subject {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Subject {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Debug for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        for subject in ["foo", "foo.bar", "foo.*", "*.bar.>", ">", "foo*.>bar"] {
+            assert!(Subject::from(subject).validate().is_ok(), "{}", subject);
+        }
+        for subject in [
+            "foo.",
+            ".foo",
+            "foo..bar",
+            "foo.>.bar",
+            "foo\x0bbar",
+            "foo.\u{a0}",
+        ] {
+            assert!(Subject::from(subject).validate().is_err(), "{:?}", subject);
+        }
+
+        assert!(Subject::from("foo.bar*").validate_literal().is_ok());
+        assert!(Subject::from("foo.*").validate_literal().is_err());
+        assert!(Subject::from("foo.>").validate_literal().is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{errors::Error, subscribe::Subscription};
+
+/// Number of match results kept in the cache before it is swept.
+const CACHE_MAX: usize = 1024;
+
+/// Number of entries left in the cache after a sweep.
+const CACHE_SWEEP: usize = 256;
+
+/// Separates the tokens of a subject.
+pub(crate) const TSEP: char = '.';
+
+/// Matches exactly one token.
+pub(crate) const PWC: &str = "*";
+
+/// Matches one or more tokens, only valid as the last token.
+pub(crate) const FWC: &str = ">";
+
+/// The subscriptions matching a published subject.
+#[derive(Debug, Default)]
+pub(crate) struct SublistResult {
+    /// Plain subscriptions, each of which gets a copy of the message.
+    pub(crate) psubs: Vec<Arc<Subscription>>,
+    /// Queue subscriptions grouped by queue name. Only one member of each
+    /// group gets the message.
+    pub(crate) qsubs: Vec<Vec<Arc<Subscription>>>,
+}
+
+/// Subject index mapping subjects, including wildcard subjects, to the
+/// subscriptions interested in them.
+///
+/// The index is a trie with one level per token. Lookups of published
+/// subjects are cached until a subscription that could change the result is
+/// inserted or removed.
+#[derive(Debug, Default)]
+pub(crate) struct Sublist {
+    root: Level,
+    cache: HashMap<String, Arc<SublistResult>>,
+}
+
+#[derive(Debug, Default)]
+struct Level {
+    nodes: HashMap<String, Node>,
+    pwc: Option<Box<Node>>,
+    fwc: Option<Box<Node>>,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    next: Level,
+    psubs: Vec<Arc<Subscription>>,
+    qsubs: HashMap<String, Vec<Arc<Subscription>>>,
+}
+
+impl Level {
+    fn is_empty(&self) -> bool {
+        self.nodes.is_empty() && self.pwc.is_none() && self.fwc.is_none()
+    }
+
+    fn node_mut(&mut self, token: &str) -> &mut Node {
+        match token {
+            PWC => self.pwc.get_or_insert_with(Default::default),
+            FWC => self.fwc.get_or_insert_with(Default::default),
+            _ => self.nodes.entry(token.to_string()).or_default(),
+        }
+    }
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.psubs.is_empty() && self.qsubs.is_empty() && self.next.is_empty()
+    }
+
+    fn insert(&mut self, sub: Arc<Subscription>) {
+        match &sub.queue {
+            Some(queue) => self.qsubs.entry(queue.clone()).or_default().push(sub),
+            None => self.psubs.push(sub),
+        }
+    }
+
+    fn remove(&mut self, sub: &Arc<Subscription>) {
+        match &sub.queue {
+            Some(queue) => {
+                if let Some(group) = self.qsubs.get_mut(queue) {
+                    group.retain(|s| !Arc::ptr_eq(s, sub));
+                    if group.is_empty() {
+                        self.qsubs.remove(queue);
+                    }
+                }
+            }
+            None => self.psubs.retain(|s| !Arc::ptr_eq(s, sub)),
+        }
+    }
+}
+
+impl SublistResult {
+    /// Add the subscriptions of `node`, merging queue groups that share a
+    /// name.
+    fn add_node(&mut self, node: &Node) {
+        self.psubs.extend(node.psubs.iter().cloned());
+        for (queue, subs) in &node.qsubs {
+            let group = self
+                .qsubs
+                .iter_mut()
+                .find(|group| group[0].queue.as_ref() == Some(queue));
+            match group {
+                Some(group) => group.extend(subs.iter().cloned()),
+                None => self.qsubs.push(subs.clone()),
+            }
+        }
+    }
+}
+
+impl Sublist {
+    pub(crate) fn new() -> Sublist {
+        Sublist::default()
+    }
+
+    /// Insert `sub` under its subject.
+    pub(crate) fn insert(&mut self, sub: Arc<Subscription>) -> Result<(), Error> {
+        let tokens = tokenize(&sub.subject)?;
+        let mut level = &mut self.root;
+        let (last, rest) = tokens.split_last().ok_or(Error::InvalidSubject)?;
+        for token in rest {
+            level = &mut level.node_mut(token).next;
+        }
+        level.node_mut(last).insert(sub.clone());
+
+        self.invalidate(&sub.subject);
+        Ok(())
+    }
+
+    /// Remove `sub`. Removing a subscription that is not present is a no-op.
+    pub(crate) fn remove(&mut self, sub: &Arc<Subscription>) {
+        let tokens = match tokenize(&sub.subject) {
+            Ok(tokens) => tokens,
+            Err(_) => return,
+        };
+        remove_from_level(&mut self.root, &tokens, sub);
+
+        self.invalidate(&sub.subject);
+    }
+
+    /// Returns every subscription whose subject matches the literal
+    /// `subject`.
+    pub(crate) fn matches(&mut self, subject: &str) -> Arc<SublistResult> {
+        if let Some(result) = self.cache.get(subject) {
+            return result.clone();
+        }
+
+        let tokens: Vec<&str> = subject.split(TSEP).collect();
+        let mut result = SublistResult::default();
+        match_level(&self.root, &tokens, &mut result);
+        let result = Arc::new(result);
+
+        if self.cache.len() >= CACHE_MAX {
+            let evict: Vec<String> = self
+                .cache
+                .keys()
+                .take(CACHE_MAX - CACHE_SWEEP)
+                .cloned()
+                .collect();
+            for key in evict {
+                self.cache.remove(&key);
+            }
+        }
+        self.cache.insert(subject.to_string(), result.clone());
+        result
+    }
+
+    /// Drop the cached results that a change to `subject` could affect.
+    fn invalidate(&mut self, subject: &str) {
+        self.cache
+            .retain(|literal, _| !subject_matches(subject, literal));
+    }
+}
+
+/// Split a subscription subject into tokens, checking that no token is
+/// empty and that `>` only appears as the last token.
+fn tokenize(subject: &str) -> Result<Vec<&str>, Error> {
+    let tokens: Vec<&str> = subject.split(TSEP).collect();
+    for (i, token) in tokens.iter().enumerate() {
+        if token.is_empty() || (*token == FWC && i != tokens.len() - 1) {
+            return Err(Error::InvalidSubject);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Returns `true` if the literal subject `literal` matches `pattern`, which
+/// may contain wildcards.
+pub fn subject_matches(pattern: &str, literal: &str) -> bool {
+    let mut literal = literal.split(TSEP);
+    for token in pattern.split(TSEP) {
+        match (token, literal.next()) {
+            (FWC, Some(_)) => return true,
+            (PWC, Some(_)) => {}
+            (token, Some(lit)) if token == lit => {}
+            _ => return false,
+        }
+    }
+    literal.next().is_none()
+}
+
+fn match_level(level: &Level, tokens: &[&str], result: &mut SublistResult) {
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    if let Some(fwc) = &level.fwc {
+        result.add_node(fwc);
+    }
+    if let Some(pwc) = &level.pwc {
+        match_node(pwc, rest, result);
+    }
+    if let Some(node) = level.nodes.get(*token) {
+        match_node(node, rest, result);
+    }
+}
+
+fn match_node(node: &Node, rest: &[&str], result: &mut SublistResult) {
+    if rest.is_empty() {
+        result.add_node(node);
+    } else {
+        match_level(&node.next, rest, result);
+    }
+}
+
+fn remove_from_level(level: &mut Level, tokens: &[&str], sub: &Arc<Subscription>) {
+    let (token, rest) = match tokens.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    match *token {
+        PWC | FWC => {
+            let slot = if *token == PWC {
+                &mut level.pwc
+            } else {
+                &mut level.fwc
+            };
+            if let Some(node) = slot {
+                remove_from_node(node, rest, sub);
+                if node.is_empty() {
+                    *slot = None;
+                }
+            }
+        }
+        _ => {
+            if let Some(node) = level.nodes.get_mut(*token) {
+                remove_from_node(node, rest, sub);
+                if node.is_empty() {
+                    level.nodes.remove(*token);
+                }
+            }
+        }
+    }
+}
+
+fn remove_from_node(node: &mut Node, rest: &[&str], sub: &Arc<Subscription>) {
+    if rest.is_empty() {
+        node.remove(sub);
+    } else {
+        remove_from_level(&mut node.next, rest, sub);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::Outbound;
+
+    fn sub(subject: &str) -> Arc<Subscription> {
+        let (outbound, _) = Outbound::channel(1, 1);
+        Arc::new(Subscription::new(
+            1,
+            subject.into(),
+            None,
+            "1",
+            false,
+            outbound,
+        ))
+    }
+
+    fn qsub(subject: &str, queue: &str) -> Arc<Subscription> {
+        let (outbound, _) = Outbound::channel(1, 1);
+        Arc::new(Subscription::new(
+            1,
+            subject.into(),
+            Some(queue.to_string()),
+            "1",
+            false,
+            outbound,
+        ))
+    }
+
+    fn subjects(result: &SublistResult) -> Vec<&str> {
+        let mut subjects: Vec<&str> = result.psubs.iter().map(|s| &s.subject[..]).collect();
+        subjects.sort_unstable();
+        subjects
+    }
+
+    #[test]
+    fn test_subject_matches() {
+        assert!(subject_matches("foo.bar", "foo.bar"));
+        assert!(subject_matches("foo.*", "foo.bar"));
+        assert!(subject_matches("*.bar", "foo.bar"));
+        assert!(subject_matches("foo.>", "foo.bar.baz"));
+        assert!(subject_matches(">", "foo"));
+        assert!(!subject_matches("foo.*", "foo"));
+        assert!(!subject_matches("foo.*", "foo.bar.baz"));
+        assert!(!subject_matches("foo.>", "foo"));
+        assert!(!subject_matches("foo.bar", "foo.baz"));
+    }
+
+    #[test]
+    fn test_insert_invalid() {
+        let mut list = Sublist::new();
+        assert!(list.insert(sub("foo..bar")).is_err());
+        assert!(list.insert(sub("foo.>.bar")).is_err());
+        assert!(list.insert(sub(".foo")).is_err());
+        assert!(list.insert(sub("foo.>")).is_ok());
+    }
+
+    #[test]
+    fn test_match_wildcards() {
+        let mut list = Sublist::new();
+        for subject in &["orders.new", "orders.*", "orders.>", "*.new", ">", "other"] {
+            list.insert(sub(subject)).unwrap();
+        }
+        assert_eq!(
+            subjects(&list.matches("orders.new")),
+            vec!["*.new", ">", "orders.*", "orders.>", "orders.new"]
+        );
+        assert_eq!(
+            subjects(&list.matches("orders.eu.new")),
+            vec![">", "orders.>"]
+        );
+        assert_eq!(subjects(&list.matches("orders")), vec![">"]);
+        assert_eq!(subjects(&list.matches("other")), vec![">", "other"]);
+    }
+
+    #[test]
+    fn test_cache_invalidation() {
+        let mut list = Sublist::new();
+        let star = sub("foo.*");
+        list.insert(star.clone()).unwrap();
+        assert_eq!(subjects(&list.matches("foo.bar")), vec!["foo.*"]);
+
+        let tail = sub("foo.>");
+        list.insert(tail.clone()).unwrap();
+        assert_eq!(subjects(&list.matches("foo.bar")), vec!["foo.*", "foo.>"]);
+
+        list.remove(&star);
+        assert_eq!(subjects(&list.matches("foo.bar")), vec!["foo.>"]);
+        list.remove(&tail);
+        assert!(list.matches("foo.bar").psubs.is_empty());
+        assert!(list.root.is_empty());
+    }
+
+    #[test]
+    fn test_match_queues() {
+        let mut list = Sublist::new();
+        let plain = sub("jobs.new");
+        let w1 = qsub("jobs.new", "workers");
+        let w2 = qsub("jobs.*", "workers");
+        let a1 = qsub("jobs.>", "audit");
+        for s in &[&plain, &w1, &w2, &a1] {
+            list.insert((*s).clone()).unwrap();
+        }
+
+        let result = list.matches("jobs.new");
+        assert_eq!(subjects(&result), vec!["jobs.new"]);
+        let mut groups: Vec<(String, usize)> = result
+            .qsubs
+            .iter()
+            .map(|group| (group[0].queue.clone().unwrap(), group.len()))
+            .collect();
+        groups.sort();
+        assert_eq!(
+            groups,
+            vec![("audit".to_string(), 1), ("workers".to_string(), 2)]
+        );
+
+        // Members leaving are no longer matched.
+        list.remove(&w1);
+        list.remove(&a1);
+        let result = list.matches("jobs.new");
+        assert_eq!(result.qsubs.len(), 1);
+        assert!(Arc::ptr_eq(&result.qsubs[0][0], &w2));
+
+        list.remove(&w2);
+        list.remove(&plain);
+        assert!(list.root.is_empty());
+    }
+}
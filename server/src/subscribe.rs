@@ -1,104 +1,304 @@
 use bytes::Bytes;
-use futures_util::SinkExt;
-use log::info;
-use tokio::sync::broadcast;
-use std::pin::Pin;
-use tokio_stream::{Stream, StreamExt, StreamMap};
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
 
-use crate::{connection::Connection, errors::Error, protocol::NatsProtocol, server::Db};
+use crate::{
+    connection::Connection,
+    errors::Error,
+    outbound::Outbound,
+    protocol::{Msg, ServerOp},
+    publish::Message,
+    server::Db,
+    subject::{self, Subject},
+    sublist,
+    unsubscribe::Unsubscribe,
+};
 
 #[derive(Clone, Debug)]
 pub struct Subscribe {
-    pub channels: Vec<String>,
+    pub subject: Subject,
+    pub queue: Option<String>,
+    /// Subscription id chosen by the client, echoed back in every `MSG`.
+    pub sid: String,
 }
 
-type Messages = Pin<Box<dyn Stream<Item = Bytes> + Send>>;
+/// A subscription registered in the `Db`. Messages published to a matching
+/// subject are queued directly on the owning connection's `outbound` queue.
+#[derive(Debug)]
+pub(crate) struct Subscription {
+    /// Id of the client owning the subscription.
+    pub(crate) cid: u64,
+    pub(crate) subject: Subject,
+    pub(crate) queue: Option<String>,
+    /// Kept as `Bytes` so every delivered message shares it.
+    pub(crate) sid: Bytes,
+    /// Whether the client negotiated headers when it subscribed. Clients
+    /// that did not only get the payload.
+    headers: bool,
+    outbound: Outbound,
+    /// Number of messages delivered so far.
+    delivered: AtomicUsize,
+    /// Auto-unsubscribe limit, `usize::MAX` if there is none.
+    max: AtomicUsize,
+    /// Where the sid goes once the limit is reached, for the client's
+    /// `Subscriptions` to forget it.
+    done: Arc<Mutex<Vec<Bytes>>>,
+}
 
+/// The subscriptions of a single client, keyed by sid. Dropping it removes
+/// every subscription from the `Db`.
+#[derive(Debug)]
+pub(crate) struct Subscriptions {
+    db: Db,
+    cid: u64,
+    /// Shared by all subscriptions of the client, so messages are delivered
+    /// in the order they were published.
+    outbound: Outbound,
+    subs: HashMap<Bytes, Arc<Subscription>>,
+    /// Sids of the subscriptions that reached their auto-unsubscribe limit.
+    /// They are already removed from the `Db`.
+    done: Arc<Mutex<Vec<Bytes>>>,
+}
 
-impl Subscribe {
-    /// Creates a new `Subscribe` command to listen on the specified channels.
-    pub(crate) fn new(channels: &[String]) -> Subscribe {
-        Subscribe {
-            channels: channels.to_vec(),
+impl Subscription {
+    /// Creates a subscription of client `cid`, delivering its messages to
+    /// `outbound`.
+    pub(crate) fn new(
+        cid: u64,
+        subject: Subject,
+        queue: Option<String>,
+        sid: impl ToString,
+        headers: bool,
+        outbound: Outbound,
+    ) -> Subscription {
+        Subscription {
+            cid,
+            subject,
+            queue,
+            sid: Bytes::from(sid.to_string()),
+            headers,
+            outbound,
+            delivered: AtomicUsize::new(0),
+            max: AtomicUsize::new(usize::MAX),
+            done: Arc::default(),
         }
     }
 
-    pub(crate) async fn apply(
-        mut self,
-        db: &Db,
-        dst: &mut Connection,
-    ) -> Result<(), Error> {
-        let mut subscriptions = StreamMap::new();
+    /// Queue a message for delivery. Returns `false` if it was dropped or the
+    /// subscription already reached its auto-unsubscribe limit.
+    pub(crate) fn deliver(&self, msg: &Message) -> bool {
+        // Claim a delivery below the limit first, so concurrent publishers
+        // never deliver more than `max` messages.
+        let max = self.max.load(Ordering::Acquire);
+        let claimed = self
+            .delivered
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then(|| n + 1)
+            });
+        let n = match claimed {
+            Ok(n) => n,
+            Err(_) => return false,
+        };
 
-        loop { 
-            for channel_name in self.channels.drain(..) {
-                subscribe_to_channel(channel_name, &mut subscriptions, db, dst)?;
-            }
-            // let res = dst.stream.next().await;
-            
-            tokio::select! {
-                Some((channel_name, msg)) = subscriptions.next() => {
-                    // write frame to dst
-                    dst.stream.send((channel_name, msg)).await?;
-                }
-                //res = dst.stream.next() 
-                res = dst.stream.next() => {
-                    info!("recv new command {:?}", res);
-                    let protocol = match res {
-                        Some(protocol) => protocol?,
-                        None => return Ok(()),
-                    };
-                    let _ = handle_command(protocol, &mut self.channels, &mut subscriptions, dst);
-                }
+        let msg = Msg {
+            subject: msg.subject.clone(),
+            sid: self.sid.clone(),
+            reply: msg.reply.clone(),
+            headers: msg.headers.clone().filter(|_| self.headers),
+            payload: msg.payload.clone(),
+        };
+        if self.outbound.try_send(ServerOp::Msg(msg)) {
+            if n + 1 == max {
+                self.done.lock().unwrap().push(self.sid.clone());
             }
+            true
+        } else {
+            self.delivered.fetch_sub(1, Ordering::AcqRel);
+            false
         }
+    }
 
-        // Ok(())
+    /// Returns `true` once the subscription delivered as many messages as it
+    /// was limited to with `UNSUB <sid> <max_msgs>`.
+    pub(crate) fn is_done(&self) -> bool {
+        self.delivered.load(Ordering::Acquire) >= self.max.load(Ordering::Acquire)
     }
 }
 
-fn subscribe_to_channel(
-    channel_name: String,
-    subscriptions: &mut StreamMap<String, Messages>,
-    db: &Db,
-    dst: &mut Connection,
-) -> Result<(), Error> {
-    let mut rx = db.subscribe(channel_name.clone());
-
-    let rx = Box::pin(async_stream::stream! {
-        loop {
-            match rx.recv().await {
-                Ok(msg) => yield msg,
-                // If we lagged in consuming messages, just resume.
-                Err(broadcast::error::RecvError::Lagged(_)) => {}
-                Err(_) => break,
+impl Subscriptions {
+    pub(crate) fn new(db: Db, cid: u64, outbound: Outbound) -> Subscriptions {
+        Subscriptions {
+            db,
+            cid,
+            outbound,
+            subs: HashMap::new(),
+            done: Arc::default(),
+        }
+    }
+
+    /// Create a subscription delivering to this client. It still has to be
+    /// registered with `insert`.
+    pub(crate) fn subscription(&self, sub: Subscribe, headers: bool) -> Arc<Subscription> {
+        // The subject of a `SUB` is a slice of the read buffer. Copy it, or
+        // the subscription would keep the whole buffer alive for as long as
+        // it lives.
+        let mut sub = Subscription::new(
+            self.cid,
+            Subject::from(sub.subject.as_str()),
+            sub.queue,
+            sub.sid,
+            headers,
+            self.outbound.clone(),
+        );
+        sub.done = self.done.clone();
+        Arc::new(sub)
+    }
+
+    /// Returns `true` if the client has a live subscription with `sid`.
+    pub(crate) fn contains(&self, sid: &str) -> bool {
+        self.subs
+            .get(sid.as_bytes())
+            .is_some_and(|sub| !sub.is_done())
+    }
+
+    /// Number of subscriptions the client has.
+    pub(crate) fn len(&mut self) -> usize {
+        self.prune();
+        self.subs.len()
+    }
+
+    /// Forget the subscriptions that reached their auto-unsubscribe limit
+    /// since the last call.
+    fn prune(&mut self) {
+        let done = std::mem::take(&mut *self.done.lock().unwrap());
+        for sid in done {
+            // The sid may have been reused by a new subscription since.
+            if self.subs.get(&sid).is_some_and(|sub| sub.is_done()) {
+                self.subs.remove(&sid);
             }
         }
-    });
+    }
 
-    // Track subscription in this client's subscription set.
-    subscriptions.insert(channel_name.clone(), rx);
+    /// Deliver `msg` to the first of the client's subscriptions matching its
+    /// subject. Returns `false` if there is none or it could not take it.
+    pub(crate) fn deliver(&mut self, msg: &Message) -> bool {
+        let sub = self.subs.values().find(|sub| {
+            !sub.is_done() && sublist::subject_matches(sub.subject.as_str(), msg.subject.as_str())
+        });
+        let sub = match sub {
+            Some(sub) => sub.clone(),
+            None => return false,
+        };
+        let delivered = sub.deliver(msg);
+        if delivered && sub.is_done() {
+            self.subs.remove(&sub.sid);
+            self.db.unsubscribe(&sub);
+        }
+        delivered
+    }
+
+    /// Register `sub` in the `Db` and track it.
+    fn insert(&mut self, sub: Arc<Subscription>) -> Result<(), Error> {
+        self.prune();
+        self.db.subscribe(sub.clone())?;
+        self.subs.insert(sub.sid.clone(), sub);
+        Ok(())
+    }
+
+    /// Remove `sid` now, or once it has delivered `max_msgs` messages.
+    pub(crate) fn unsubscribe(&mut self, unsub: Unsubscribe) {
+        self.prune();
+        if let Some(sub) = self.subs.get(unsub.sid.as_bytes()) {
+            if let Some(max) = unsub.max_msgs {
+                sub.max.store(max, Ordering::Release);
+            }
+            if unsub.max_msgs.is_none() || sub.is_done() {
+                self.remove(&unsub.sid);
+            }
+        }
+    }
 
-    // TODO Response with successful subscription
+    fn remove(&mut self, sid: &str) {
+        if let Some(sub) = self.subs.remove(sid.as_bytes()) {
+            self.db.unsubscribe(&sub);
+        }
+    }
+}
 
-    Ok(())
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for sub in self.subs.values() {
+            self.db.unsubscribe(sub);
+        }
+    }
 }
 
-fn handle_command(
-    protocol: NatsProtocol,
-    subscribe_to: &mut Vec<String>,
-    subscriptions: &mut StreamMap<String, Messages>,
-    dst: &mut Connection,
-) -> Result<(), Error> {
-    info!("{:?}", protocol);
-    match protocol {
-        NatsProtocol::Sub(s)=> {
-            subscribe_to.extend(s.channels.into_iter());
+impl Subscribe {
+    /// Creates a new `Subscribe` command to listen on `subject`.
+    pub(crate) fn new(subject: Subject, queue: Option<&str>, sid: impl ToString) -> Subscribe {
+        Subscribe {
+            subject,
+            queue: queue.map(|q| q.to_string()),
+            sid: sid.to_string(),
+        }
+    }
+
+    /// Strict checks of the arguments, done for pedantic clients.
+    fn validate(&self) -> Result<(), Error> {
+        self.subject.validate()?;
+        let queue = self.queue.as_deref().is_none_or(subject::is_valid_name);
+        if !queue || !subject::is_valid_name(&self.sid) {
+            return Err(Error::InvalidSubject);
         }
-        _ => {
+        Ok(())
+    }
 
+    pub(crate) async fn apply(self, dst: &mut Connection) -> Result<(), Error> {
+        if dst.opts.pedantic {
+            self.validate()?;
         }
+        // A sid that is already in use keeps its original subscription.
+        if !dst.subs.contains(&self.sid) {
+            let sub = dst.subs.subscription(self, dst.opts.headers);
+            dst.subs.insert(sub)?;
+        }
+
+        dst.ok().await
     }
+}
 
-    Ok(())
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{info::ServerInfo, options::ServerOptions};
+
+    #[test]
+    fn test_auto_unsubscribe_forgets_subs() {
+        let opts = ServerOptions::default();
+        let db = Db::new(ServerInfo::new("127.0.0.1", 4222, &opts), opts);
+        // Nothing writes the messages out, so the pending bytes add up.
+        let (outbound, mut rx) = Outbound::channel(16, usize::MAX);
+        let mut subs = Subscriptions::new(db.clone(), 1, outbound);
+
+        // A request each time: a new inbox, limited to the one reply.
+        for i in 0..1000 {
+            let inbox = Subject::from(format!("inbox.{}", i));
+            let sub = subs.subscription(Subscribe::new(inbox.clone(), None, i), false);
+            subs.insert(sub).unwrap();
+            subs.unsubscribe(Unsubscribe::new(i, Some(1)));
+            let reply = Message {
+                subject: inbox,
+                reply: None,
+                headers: None,
+                payload: Bytes::from_static(b"hi"),
+            };
+            assert_eq!(db.publish(reply, None), 1);
+            assert!(rx.try_recv().is_ok());
+            assert!(subs.len() <= 1);
+        }
+        assert_eq!(subs.len(), 0);
+    }
+}
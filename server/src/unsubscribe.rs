@@ -0,0 +1,25 @@
+use crate::{connection::Connection, errors::Error};
+
+#[derive(Clone, Debug)]
+pub struct Unsubscribe {
+    pub sid: String,
+    /// Unsubscribe automatically once this many messages have been delivered.
+    pub max_msgs: Option<usize>,
+}
+
+impl Unsubscribe {
+    /// Creates a new `Unsubscribe` command for the subscription `sid`.
+    pub(crate) fn new(sid: impl ToString, max_msgs: Option<usize>) -> Unsubscribe {
+        Unsubscribe {
+            sid: sid.to_string(),
+            max_msgs,
+        }
+    }
+
+    /// Remove the subscription, now or after `max_msgs` deliveries. Unknown
+    /// sids are ignored.
+    pub(crate) async fn apply(self, dst: &mut Connection) -> Result<(), Error> {
+        dst.subs.unsubscribe(self);
+        dst.ok().await
+    }
+}